@@ -0,0 +1,11 @@
+-- Revenue recognized over time for every paid contract
+CREATE TABLE IF NOT EXISTS revenue_schedule (
+    id SERIAL PRIMARY KEY,
+    contract_id INTEGER NOT NULL REFERENCES contract(id),
+    kind TEXT NOT NULL CHECK (kind IN ('license', 'support')),
+    amount NUMERIC(10, 2) NOT NULL,
+    recognition_date TIMESTAMP NOT NULL,
+    is_deleted BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS revenue_schedule_contract_id_idx ON revenue_schedule (contract_id);
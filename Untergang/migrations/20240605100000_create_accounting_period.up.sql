@@ -0,0 +1,5 @@
+-- Closed accounting periods, one row per closed month
+CREATE TABLE IF NOT EXISTS accounting_period (
+    period_start DATE PRIMARY KEY,
+    closed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
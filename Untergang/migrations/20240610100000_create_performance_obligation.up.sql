@@ -0,0 +1,42 @@
+-- Every contract is split into a perpetual license and one support obligation per supported year
+CREATE TABLE IF NOT EXISTS performance_obligation (
+    id SERIAL PRIMARY KEY,
+    contract_id INTEGER NOT NULL REFERENCES contract(id),
+    kind TEXT NOT NULL CHECK (kind IN ('license', 'support')),
+    support_year INTEGER,
+    allocated_price NUMERIC(10, 2) NOT NULL,
+    recognition_rule TEXT NOT NULL CHECK (recognition_rule IN ('point_in_time', 'ratably')),
+    is_deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    CONSTRAINT check_support_year CHECK (
+        (kind = 'license' AND support_year IS NULL) OR
+        (kind = 'support' AND support_year IS NOT NULL)
+    )
+);
+
+CREATE INDEX IF NOT EXISTS performance_obligation_contract_id_idx ON performance_obligation (contract_id);
+
+-- Split the existing contracts the same way new ones are split: in proportion to the list
+-- price of the license and 1 000 zł for every year of support
+INSERT INTO performance_obligation (contract_id, kind, support_year, allocated_price, recognition_rule)
+WITH allocation AS (
+    SELECT c.id, c.price, c.years_supported,
+        CASE
+            WHEN c.years_supported <= 0 OR s.price + c.years_supported * 1000 = 0 THEN c.price
+            ELSE ROUND(c.price * s.price / (s.price + c.years_supported * 1000), 2)
+        END AS license_price
+    FROM contract c
+    JOIN software s ON s.id = c.product_id
+    WHERE NOT EXISTS (SELECT 1 FROM performance_obligation o WHERE o.contract_id = c.id)
+)
+SELECT id, 'license', NULL, license_price, 'point_in_time'
+FROM allocation
+UNION ALL
+SELECT a.id, 'support', year,
+    CASE
+        WHEN year = a.years_supported THEN (a.price - a.license_price)
+            - TRUNC((a.price - a.license_price) / a.years_supported, 2) * (a.years_supported - 1)
+        ELSE TRUNC((a.price - a.license_price) / a.years_supported, 2)
+    END,
+    'ratably'
+FROM allocation a
+CROSS JOIN LATERAL generate_series(1, a.years_supported) AS year;
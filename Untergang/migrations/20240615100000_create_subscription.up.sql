@@ -0,0 +1,19 @@
+-- Software sold as a subscription renewed every 1-24 months
+CREATE TABLE IF NOT EXISTS subscription (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    contract_type TEXT NOT NULL CHECK (contract_type IN ('private', 'corporate')),
+    personal_client_pesel VARCHAR(11) REFERENCES personal_client(pesel),
+    company_client_krs VARCHAR(10) REFERENCES company_client(krs),
+    product_id INTEGER NOT NULL REFERENCES software(id),
+    renewal_period_months INTEGER NOT NULL CHECK (renewal_period_months BETWEEN 1 AND 24),
+    price NUMERIC(10, 2) NOT NULL,
+    start_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    status TEXT NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'cancelled')),
+    cancelled_at TIMESTAMP,
+    is_deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    CONSTRAINT check_subscription_client_type CHECK (
+        (contract_type = 'private' AND personal_client_pesel IS NOT NULL AND company_client_krs IS NULL) OR
+        (contract_type = 'corporate' AND personal_client_pesel IS NULL AND company_client_krs IS NOT NULL)
+    )
+);
@@ -0,0 +1,15 @@
+-- Payments can now settle either a contract or one renewal period of a subscription
+ALTER TABLE payment
+    ADD COLUMN IF NOT EXISTS subscription_id INTEGER REFERENCES subscription(id),
+    ADD COLUMN IF NOT EXISTS period_number INTEGER;
+
+ALTER TABLE payment
+    ADD CONSTRAINT check_payment_target CHECK (
+        (subscription_id IS NULL AND period_number IS NULL) OR
+        (subscription_id IS NOT NULL AND period_number IS NOT NULL AND contract_id IS NULL)
+    );
+
+-- A renewal period can only be paid once
+CREATE UNIQUE INDEX IF NOT EXISTS payment_subscription_period_idx
+    ON payment (subscription_id, period_number)
+    WHERE subscription_id IS NOT NULL AND amount > 0 AND is_deleted = FALSE;
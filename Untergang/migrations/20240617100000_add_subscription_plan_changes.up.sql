@@ -0,0 +1,31 @@
+-- After a plan change the renewal periods are counted again from the billing anchor,
+-- period_offset keeps the period numbers growing across changes
+ALTER TABLE subscription
+    ADD COLUMN IF NOT EXISTS billing_anchor TIMESTAMP,
+    ADD COLUMN IF NOT EXISTS period_offset INTEGER NOT NULL DEFAULT 0;
+
+UPDATE subscription SET billing_anchor = start_date WHERE billing_anchor IS NULL;
+
+ALTER TABLE subscription
+    ALTER COLUMN billing_anchor SET NOT NULL,
+    ALTER COLUMN billing_anchor SET DEFAULT CURRENT_TIMESTAMP;
+
+-- Subscription payments are either a renewal or one side of a plan change proration
+ALTER TABLE payment
+    ADD COLUMN IF NOT EXISTS subscription_payment_kind TEXT
+        CHECK (subscription_payment_kind IN ('renewal', 'proration_credit', 'proration_charge'));
+
+UPDATE payment SET subscription_payment_kind = 'renewal'
+WHERE subscription_id IS NOT NULL AND subscription_payment_kind IS NULL;
+
+ALTER TABLE payment
+    ADD CONSTRAINT check_subscription_payment_kind CHECK (
+        (subscription_id IS NULL) = (subscription_payment_kind IS NULL)
+    );
+
+DROP INDEX IF EXISTS payment_subscription_period_idx;
+
+-- A renewal period can only be paid once
+CREATE UNIQUE INDEX IF NOT EXISTS payment_subscription_period_idx
+    ON payment (subscription_id, period_number)
+    WHERE subscription_payment_kind = 'renewal' AND is_deleted = FALSE;
@@ -0,0 +1,18 @@
+-- Allowed support period per product, every year costs 1 000 zł on top of the product price
+ALTER TABLE software
+    ADD COLUMN IF NOT EXISTS min_years_supported INTEGER NOT NULL DEFAULT 1,
+    ADD COLUMN IF NOT EXISTS max_years_supported INTEGER NOT NULL DEFAULT 3;
+
+ALTER TABLE software
+    ADD CONSTRAINT check_years_supported_range CHECK (
+        min_years_supported >= 0 AND min_years_supported <= max_years_supported
+    );
+
+-- How the contract price was calculated, empty for contracts priced before the breakdown existed
+ALTER TABLE contract
+    ADD COLUMN IF NOT EXISTS base_price NUMERIC(10, 2),
+    ADD COLUMN IF NOT EXISTS support_price NUMERIC(10, 2),
+    ADD COLUMN IF NOT EXISTS product_discount_rate NUMERIC(7, 5),
+    ADD COLUMN IF NOT EXISTS product_discount NUMERIC(10, 2),
+    ADD COLUMN IF NOT EXISTS returning_customer_discount_rate NUMERIC(7, 5),
+    ADD COLUMN IF NOT EXISTS returning_customer_discount NUMERIC(10, 2);
@@ -0,0 +1,33 @@
+-- Contract lifecycle: draft -> awaiting_payment -> signed -> active -> expired / cancelled / refunded
+ALTER TABLE contract
+    ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'draft'
+        CHECK (status IN ('draft', 'awaiting_payment', 'signed', 'active', 'expired', 'cancelled', 'refunded'));
+
+UPDATE contract c SET status = CASE
+    WHEN c.is_deleted THEN 'cancelled'
+    WHEN c.is_paid THEN 'active'
+    WHEN EXISTS (
+        SELECT 1 FROM payment p WHERE p.contract_id = c.id AND p.is_deleted = FALSE AND p.amount > 0
+    ) THEN 'awaiting_payment'
+    ELSE 'draft'
+END;
+
+CREATE TABLE IF NOT EXISTS contract_status_history (
+    id SERIAL PRIMARY KEY,
+    contract_id INTEGER NOT NULL REFERENCES contract(id),
+    -- empty for the status a contract was created with
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS contract_status_history_contract_idx
+    ON contract_status_history (contract_id);
+
+INSERT INTO contract_status_history (contract_id, from_status, to_status)
+SELECT id, NULL, status FROM contract;
+
+ALTER TABLE contract
+    DROP COLUMN IF EXISTS is_signed,
+    DROP COLUMN IF EXISTS is_paid,
+    DROP COLUMN IF EXISTS is_deleted;
@@ -0,0 +1,2 @@
+-- Why a compensating (negative) payment was made, empty for regular payments
+ALTER TABLE payment ADD COLUMN IF NOT EXISTS reason TEXT;
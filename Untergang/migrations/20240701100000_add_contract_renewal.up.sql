@@ -0,0 +1,4 @@
+-- Contract this one renews, set by POST /contract/{id}/renew
+ALTER TABLE contract ADD COLUMN IF NOT EXISTS renewed_from_id INTEGER REFERENCES contract(id);
+
+CREATE INDEX IF NOT EXISTS contract_renewed_from_idx ON contract (renewed_from_id);
@@ -0,0 +1,4 @@
+-- Payment a refund gives money back for, set by POST /payment/{id}/refund
+ALTER TABLE payment ADD COLUMN IF NOT EXISTS refunded_payment_id INTEGER REFERENCES payment(id);
+
+CREATE INDEX IF NOT EXISTS payment_refunded_payment_idx ON payment (refunded_payment_id);
@@ -0,0 +1,14 @@
+-- Installment plan of a contract, installments are paid in order of installment_number
+CREATE TABLE IF NOT EXISTS installment_schedule (
+    id SERIAL PRIMARY KEY,
+    contract_id INTEGER NOT NULL REFERENCES contract(id),
+    installment_number INTEGER NOT NULL CHECK (installment_number > 0),
+    amount NUMERIC(10, 2) NOT NULL CHECK (amount > 0),
+    due_date TIMESTAMP NOT NULL,
+    -- payment that settled the installment, empty while it is still due
+    payment_id INTEGER REFERENCES payment(id),
+    UNIQUE (contract_id, installment_number)
+);
+
+CREATE INDEX IF NOT EXISTS installment_schedule_unpaid_due_date_idx
+    ON installment_schedule (due_date) WHERE payment_id IS NULL;
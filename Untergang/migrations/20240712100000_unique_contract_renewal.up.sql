@@ -0,0 +1,4 @@
+-- An expired contract can only be renewed once
+DROP INDEX IF EXISTS contract_renewed_from_idx;
+
+CREATE UNIQUE INDEX IF NOT EXISTS contract_renewed_from_key ON contract (renewed_from_id);
@@ -0,0 +1,13 @@
+-- Part of a plan change the client still owes, it is paid together with the next renewal
+CREATE TABLE IF NOT EXISTS subscription_charge (
+    id SERIAL PRIMARY KEY,
+    subscription_id INTEGER NOT NULL REFERENCES subscription(id),
+    period_number INTEGER NOT NULL,
+    amount NUMERIC(10, 2) NOT NULL CHECK (amount > 0),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- payment that settled the charge, empty while it is still due
+    payment_id INTEGER REFERENCES payment(id)
+);
+
+CREATE INDEX IF NOT EXISTS subscription_charge_unpaid_idx
+    ON subscription_charge (subscription_id) WHERE payment_id IS NULL;
@@ -1,5 +1,5 @@
 use bigdecimal::BigDecimal;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -29,7 +29,7 @@ pub enum Client {
     Company(CompanyClient),
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(tag = "type", content = "value")]
 pub enum ClientId {
     #[serde(rename = "individual")]
@@ -38,6 +38,33 @@ pub enum ClientId {
     Company(String),
 }
 
+// Kind of client without the identifier, tagged the same way as ClientId
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ClientKind {
+    #[serde(rename = "individual")]
+    Individual,
+    #[serde(rename = "company")]
+    Company,
+}
+
+impl ClientKind {
+    pub fn client_id(self, id: String) -> ClientId {
+        match self {
+            ClientKind::Individual => ClientId::Individual(id),
+            ClientKind::Company => ClientId::Company(id),
+        }
+    }
+
+    // contracts store the kind of client as their contract_type
+    pub fn from_contract_type(contract_type: &str) -> Option<Self> {
+        match contract_type {
+            "private" => Some(ClientKind::Individual),
+            "corporate" => Some(ClientKind::Company),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
 pub struct Contract {
     pub id: i32,
@@ -47,9 +74,122 @@ pub struct Contract {
     pub start_date: DateTime<Utc>,
     pub end_date: DateTime<Utc>,
     pub years_supported: i32,
-    pub is_signed: bool,
-    pub is_paid: bool,
-    pub is_deleted: bool,
+    pub status: ContractStatus,
+    // expired contract this one was renewed from
+    pub renewed_from_id: Option<i32>,
+}
+
+// Draft -> AwaitingPayment -> Signed -> Active -> Expired / Cancelled / Refunded,
+// the allowed transitions live in db::contracts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ContractStatus {
+    // created, nothing paid yet
+    #[serde(rename = "draft")]
+    Draft,
+    // partially paid
+    #[serde(rename = "awaiting_payment")]
+    AwaitingPayment,
+    // fully paid within the signing window
+    #[serde(rename = "signed")]
+    Signed,
+    // license delivered and revenue being recognized
+    #[serde(rename = "active")]
+    Active,
+    // signing window passed without full payment
+    #[serde(rename = "expired")]
+    Expired,
+    #[serde(rename = "cancelled")]
+    Cancelled,
+    #[serde(rename = "refunded")]
+    Refunded,
+}
+
+impl ContractStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContractStatus::Draft => "draft",
+            ContractStatus::AwaitingPayment => "awaiting_payment",
+            ContractStatus::Signed => "signed",
+            ContractStatus::Active => "active",
+            ContractStatus::Expired => "expired",
+            ContractStatus::Cancelled => "cancelled",
+            ContractStatus::Refunded => "refunded",
+        }
+    }
+
+    pub fn from_db(status: &str) -> Option<Self> {
+        match status {
+            "draft" => Some(ContractStatus::Draft),
+            "awaiting_payment" => Some(ContractStatus::AwaitingPayment),
+            "signed" => Some(ContractStatus::Signed),
+            "active" => Some(ContractStatus::Active),
+            "expired" => Some(ContractStatus::Expired),
+            "cancelled" => Some(ContractStatus::Cancelled),
+            "refunded" => Some(ContractStatus::Refunded),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContractDetails {
+    #[serde(flatten)]
+    pub contract: Contract,
+    pub payments: Vec<Payment>,
+    pub balance: ContractBalance,
+    // empty for contracts paid without a plan
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub installments: Vec<Installment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContractBalance {
+    pub price: BigDecimal,
+    // sum of the payments received
+    pub total_paid: BigDecimal,
+    // sum of the money given back, as a positive amount
+    pub total_refunded: BigDecimal,
+    pub remaining_due: BigDecimal,
+}
+
+impl ContractBalance {
+    // What the client has actually paid once refunds are taken out
+    pub fn net_paid(&self) -> BigDecimal {
+        &self.total_paid - &self.total_refunded
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContractPage {
+    pub contracts: Vec<ContractDetails>,
+    pub page: i64,
+    pub per_page: i64,
+    // number of contracts matching the filter across all pages
+    pub total: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct ContractFilter {
+    pub status: Option<ContractStatus>,
+    // inclusive range the contract start date has to fall in
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PriceBreakdown {
+    // list price of the product itself
+    pub base_price: BigDecimal,
+    pub years_supported: i32,
+    // 1 000 zł for every supported year
+    pub support_price: BigDecimal,
+    // base price with the support surcharge, discounts are taken from this amount
+    pub list_price: BigDecimal,
+    pub product_discount_rate: BigDecimal,
+    pub product_discount: BigDecimal,
+    pub returning_customer_discount_rate: BigDecimal,
+    pub returning_customer_discount: BigDecimal,
+    pub final_price: BigDecimal,
 }
 
 #[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
@@ -59,4 +199,341 @@ pub struct Payment {
     pub amount: BigDecimal,
     pub payment_date: DateTime<Utc>,
     pub is_deleted: bool,
+    // set on refunds and other compensating entries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // payment this refund gives money back for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refunded_payment_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefundSummary {
+    pub contract_id: i32,
+    pub status: ContractStatus,
+    // net amount the client had paid before the refund
+    pub amount_paid: BigDecimal,
+    pub amount_refunded: BigDecimal,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PaymentRefund {
+    pub refund: Payment,
+    pub contract_status: ContractStatus,
+    pub balance: ContractBalance,
+}
+
+// One installment of the plan requested with a new contract
+#[derive(Debug, Clone, Deserialize)]
+pub struct InstallmentPlanEntry {
+    pub amount: BigDecimal,
+    pub due_date: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Installment {
+    pub installment_number: i32,
+    pub amount: BigDecimal,
+    pub due_date: DateTime<Utc>,
+    // payment that settled the installment
+    pub payment_id: Option<i32>,
+    pub paid_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OverdueInstallment {
+    pub contract_id: i32,
+    pub client_id: ClientId,
+    pub installment_number: i32,
+    pub amount: BigDecimal,
+    pub due_date: DateTime<Utc>,
+    pub days_overdue: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum SubscriptionStatus {
+    #[serde(rename = "active")]
+    Active,
+    #[serde(rename = "cancelled")]
+    Cancelled,
+}
+
+impl SubscriptionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::Active => "active",
+            SubscriptionStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn from_db(status: &str) -> Option<Self> {
+        match status {
+            "active" => Some(SubscriptionStatus::Active),
+            "cancelled" => Some(SubscriptionStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Subscription {
+    pub id: i32,
+    pub name: String,
+    pub product_id: i32,
+    pub client_id: ClientId,
+    pub renewal_period_months: i32,
+    // price of a single renewal period
+    pub price: BigDecimal,
+    pub start_date: DateTime<Utc>,
+    // renewal periods are counted from here, moved forward by plan changes
+    pub billing_anchor: DateTime<Utc>,
+    // number of the first renewal period starting at the billing anchor
+    pub period_offset: i32,
+    pub status: SubscriptionStatus,
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum SubscriptionPaymentKind {
+    #[serde(rename = "renewal")]
+    Renewal,
+    #[serde(rename = "proration_credit")]
+    ProrationCredit,
+    #[serde(rename = "proration_charge")]
+    ProrationCharge,
+}
+
+impl SubscriptionPaymentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionPaymentKind::Renewal => "renewal",
+            SubscriptionPaymentKind::ProrationCredit => "proration_credit",
+            SubscriptionPaymentKind::ProrationCharge => "proration_charge",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriptionPlanChange {
+    pub subscription: Subscription,
+    // unused part of the current period returned to the client
+    pub credited: BigDecimal,
+    // remainder of the current period billed at the new plan's rate, due with the next renewal
+    pub charged: BigDecimal,
+}
+
+#[derive(Debug, Default)]
+pub struct RevenueFilter {
+    pub product_id: Option<i32>,
+    pub client_id: Option<ClientId>,
+}
+
+impl RevenueFilter {
+    // (pesel, krs) to match the contract columns against
+    pub fn client_keys(&self) -> (Option<&String>, Option<&String>) {
+        match &self.client_id {
+            Some(ClientId::Individual(pesel)) => (Some(pesel), None),
+            Some(ClientId::Company(krs)) => (None, Some(krs)),
+            None => (None, None),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProductRevenue {
+    pub product_id: i32,
+    pub product_name: String,
+    pub recognized: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentRevenue {
+    pub segment: ClientKind,
+    pub recognized: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObligationRevenue {
+    pub kind: RecognitionKind,
+    pub recognized: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevenueReport {
+    pub currency: String,
+    pub recognized: BigDecimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_product: Option<Vec<ProductRevenue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_segment: Option<Vec<SegmentRevenue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_obligation: Option<Vec<ObligationRevenue>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PredictedProductRevenue {
+    pub product_id: i32,
+    pub product_name: String,
+    pub recognized: BigDecimal,
+    pub pending: BigDecimal,
+    pub total: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PredictedSegmentRevenue {
+    pub segment: ClientKind,
+    pub recognized: BigDecimal,
+    pub pending: BigDecimal,
+    pub total: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PredictedObligationRevenue {
+    pub kind: RecognitionKind,
+    pub recognized: BigDecimal,
+    pub pending: BigDecimal,
+    pub total: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PredictedRevenueReport {
+    pub currency: String,
+    pub recognized: BigDecimal,
+    pub pending: BigDecimal,
+    pub total: BigDecimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_product: Option<Vec<PredictedProductRevenue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_segment: Option<Vec<PredictedSegmentRevenue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_obligation: Option<Vec<PredictedObligationRevenue>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum RecognitionKind {
+    #[serde(rename = "license")]
+    License,
+    #[serde(rename = "support")]
+    Support,
+}
+
+impl RecognitionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecognitionKind::License => "license",
+            RecognitionKind::Support => "support",
+        }
+    }
+
+    pub fn from_db(kind: &str) -> Option<Self> {
+        match kind {
+            "license" => Some(RecognitionKind::License),
+            "support" => Some(RecognitionKind::Support),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum RecognitionRule {
+    // recognized in full once the contract is paid
+    #[serde(rename = "point_in_time")]
+    PointInTime,
+    // recognized evenly, month by month, over the obligation's service period
+    #[serde(rename = "ratably")]
+    Ratably,
+}
+
+impl RecognitionRule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecognitionRule::PointInTime => "point_in_time",
+            RecognitionRule::Ratably => "ratably",
+        }
+    }
+
+    pub fn from_db(rule: &str) -> Option<Self> {
+        match rule {
+            "point_in_time" => Some(RecognitionRule::PointInTime),
+            "ratably" => Some(RecognitionRule::Ratably),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PerformanceObligation {
+    pub kind: RecognitionKind,
+    pub support_year: Option<i32>,
+    pub allocated_price: BigDecimal,
+    pub recognition_rule: RecognitionRule,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RevenueScheduleEntry {
+    pub kind: RecognitionKind,
+    pub amount: BigDecimal,
+    pub recognition_date: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevenueSchedule {
+    pub contract_id: i32,
+    pub recognized: BigDecimal,
+    pub deferred: BigDecimal,
+    pub entries: Vec<RevenueScheduleEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeferredRevenueEntry {
+    pub contract_id: i32,
+    pub cash_received: BigDecimal,
+    pub recognized: BigDecimal,
+    pub deferred: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeferredRevenueReport {
+    pub as_of: NaiveDate,
+    pub contracts: Vec<DeferredRevenueEntry>,
+    pub total_cash_received: BigDecimal,
+    pub total_recognized: BigDecimal,
+    pub total_deferred: BigDecimal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Granularity {
+    #[serde(rename = "month")]
+    Month,
+    #[serde(rename = "quarter")]
+    Quarter,
+    #[serde(rename = "year")]
+    Year,
+}
+
+impl Granularity {
+    pub fn months(&self) -> u32 {
+        match self {
+            Granularity::Month => 1,
+            Granularity::Quarter => 3,
+            Granularity::Year => 12,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevenueBucket {
+    pub period_start: NaiveDate,
+    pub period_end: NaiveDate,
+    pub recognized: BigDecimal,
+    pub cash_collected: BigDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevenueTimeSeries {
+    pub granularity: Granularity,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub buckets: Vec<RevenueBucket>,
 }
@@ -1,8 +1,8 @@
-use crate::client::{ClientId, Contract, Payment};
+use crate::client::{ClientId, Contract, ContractStatus, Payment, PriceBreakdown};
 use crate::handler::AppError;
-use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
+use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
-use sqlx::{Pool, Postgres};
+use sqlx::{PgConnection, Pool, Postgres};
 
 pub async fn connect_db() -> Result<Pool<Postgres>, sqlx::Error> {
     let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -72,6 +72,22 @@ pub async fn find_discounts_for_client(
     product_id: i32,
     client_id: ClientId,
 ) -> Result<Option<BigDecimal>, sqlx::Error> {
+    let (product_discount, returning_customer_discount) =
+        find_discount_rates_for_client(pool, product_id, client_id).await?;
+    Ok(Some(product_discount + returning_customer_discount))
+}
+
+// 5% for clients who already have a running contract, exact decimal rather than from_f64
+fn returning_customer_discount_rate() -> BigDecimal {
+    BigDecimal::new(5.into(), 2)
+}
+
+// Returns the best running discount for the product and the returning customer discount separately
+pub async fn find_discount_rates_for_client(
+    pool: &Pool<Postgres>,
+    product_id: i32,
+    client_id: ClientId,
+) -> Result<(BigDecimal, BigDecimal), sqlx::Error> {
     let highest_discount = sqlx::query_scalar::<_, BigDecimal>(
         "SELECT percentage FROM discount WHERE discounted_products = $1 AND is_deleted = FALSE AND start_date <= CURRENT_DATE AND end_date > CURRENT_DATE ORDER BY percentage DESC LIMIT 1",
     )
@@ -84,7 +100,7 @@ pub async fn find_discounts_for_client(
         // handle recurring clients
         ClientId::Individual(pesel) => {
             let result = sqlx::query_scalar::<_, i64>(
-                "SELECT COUNT(*) FROM contract WHERE client_id = $1 AND is_deleted = FALSE AND start_date <= CURRENT_DATE AND end_date > CURRENT_DATE",
+                "SELECT COUNT(*) FROM contract WHERE personal_client_pesel = $1 AND status NOT IN ('cancelled', 'refunded') AND start_date <= CURRENT_DATE AND end_date > CURRENT_DATE",
             )
             .bind(pesel)
             .fetch_optional(pool)
@@ -92,10 +108,7 @@ pub async fn find_discounts_for_client(
             match result {
                 Some(count) => {
                     if count >= 1 {
-                        additional_discount = Some(
-                            BigDecimal::from_f64(0.05)
-                                .expect("Failed to convert 0.05 to BigDecimal"),
-                        );
+                        additional_discount = Some(returning_customer_discount_rate());
                     }
                 }
                 None => {
@@ -105,7 +118,7 @@ pub async fn find_discounts_for_client(
         }
         ClientId::Company(krs) => {
             let result = sqlx::query_scalar::<_, i64>(
-                "SELECT COUNT(*) FROM contract WHERE client_id = $1 AND is_deleted = FALSE AND start_date <= CURRENT_DATE AND end_date > CURRENT_DATE",
+                "SELECT COUNT(*) FROM contract WHERE company_client_krs = $1 AND status NOT IN ('cancelled', 'refunded') AND start_date <= CURRENT_DATE AND end_date > CURRENT_DATE",
             )
             .bind(krs)
             .fetch_optional(pool)
@@ -113,10 +126,7 @@ pub async fn find_discounts_for_client(
             match result {
                 Some(count) => {
                     if count >= 1 {
-                        additional_discount = Some(
-                            BigDecimal::from_f64(0.05)
-                                .expect("Failed to convert 0.05 to BigDecimal"),
-                        );
+                        additional_discount = Some(returning_customer_discount_rate());
                     }
                 }
                 None => {
@@ -126,17 +136,10 @@ pub async fn find_discounts_for_client(
         }
     }
 
-    let final_discount = match highest_discount {
-        Some(discount) => match additional_discount {
-            Some(additional) => discount + additional,
-            None => discount,
-        },
-        None => match additional_discount {
-            Some(additional) => additional,
-            None => BigDecimal::from_f64(0.0).expect("Failed to convert 0.0 to BigDecimal"),
-        },
-    };
-    Ok(Some(final_discount))
+    Ok((
+        highest_discount.unwrap_or_else(|| BigDecimal::from(0)),
+        additional_discount.unwrap_or_else(|| BigDecimal::from(0)),
+    ))
 }
 
 pub async fn get_price_for_product(
@@ -162,53 +165,64 @@ pub async fn get_price_for_product(
     }
 }
 
+// Inserts the contract with its status history and performance obligations, runs in the
+// caller's transaction so a failure part-way leaves nothing behind
 pub async fn create_contract_in_db(
-    pool: &Pool<Postgres>,
-    price: &BigDecimal,
+    conn: &mut PgConnection,
+    price: &PriceBreakdown,
     product_id: &i32,
     client_id: &ClientId,
     start_date: &DateTime<Utc>,
     end_date: &DateTime<Utc>,
-    years_supported: &i32,
-) -> Result<(), sqlx::Error> {
+    renewed_from_id: Option<i32>,
+) -> Result<i32, AppError> {
+    periods::check_period_is_open(conn, start_date).await?;
+
     let (contract_type, personal_client_pesel, company_client_krs) = match client_id {
         ClientId::Individual(pesel) => ("private", Some(pesel), None),
         ClientId::Company(krs) => ("corporate", None, Some(krs)),
     };
 
-    sqlx::query!(
-        "INSERT INTO contract (contract_type, personal_client_pesel, company_client_krs, product_id, price, start_date, end_date, years_supported, is_signed, is_deleted) 
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)", 
-        contract_type, personal_client_pesel, company_client_krs, product_id, price, start_date.naive_utc(), end_date.naive_utc(), years_supported, false, false
+    let contract = sqlx::query!(
+        "INSERT INTO contract (contract_type, personal_client_pesel, company_client_krs, product_id, price, start_date, end_date, years_supported, status,
+                               base_price, support_price, product_discount_rate, product_discount, returning_customer_discount_rate, returning_customer_discount, renewed_from_id) 
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+         RETURNING id", 
+        contract_type, personal_client_pesel, company_client_krs, product_id, price.final_price, start_date.naive_utc(), end_date.naive_utc(), price.years_supported, ContractStatus::Draft.as_str(),
+        price.base_price, price.support_price, price.product_discount_rate, price.product_discount, price.returning_customer_discount_rate, price.returning_customer_discount, renewed_from_id
     )
-    .execute(pool)
-    .await?;
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| AppError::InternalServerError(format!("Failed to create contract: {}", e)))?;
+
+    contracts::record_initial_status(conn, contract.id, ContractStatus::Draft).await?;
+    obligations::create_obligations_for_contract(conn, contract.id).await?;
 
-    Ok(())
+    Ok(contract.id)
 }
 
 pub async fn check_if_client_has_contract_for_product(
-    pool: &Pool<Postgres>,
+    conn: &mut PgConnection,
     client_id: ClientId,
     product_id: i32,
 ) -> Result<bool, sqlx::Error> {
     let result = match client_id {
         ClientId::Individual(pesel) => {
             sqlx::query_scalar::<_, bool>(
-                "SELECT EXISTS(SELECT 1 FROM contract WHERE personal_client_pesel = $1 AND product_id = $2 AND is_deleted = FALSE)",
+                "SELECT EXISTS(SELECT 1 FROM contract WHERE personal_client_pesel = $1 AND product_id = $2 AND status NOT IN ('expired', 'cancelled', 'refunded'))",
             )
             .bind(pesel)
             .bind(product_id)
-            .fetch_one(pool)
+            .fetch_one(conn)
             .await?
         }
         ClientId::Company(krs) => {
             sqlx::query_scalar::<_, bool>(
-                "SELECT EXISTS(SELECT 1 FROM contract WHERE company_client_krs = $1 AND product_id = $2 AND is_deleted = FALSE)",
+                "SELECT EXISTS(SELECT 1 FROM contract WHERE company_client_krs = $1 AND product_id = $2 AND status NOT IN ('expired', 'cancelled', 'refunded'))",
             )
             .bind(krs)
             .bind(product_id)
-            .fetch_one(pool)
+            .fetch_one(conn)
             .await?
         }
     };
@@ -223,9 +237,9 @@ pub async fn get_contract_by_id(
     match client_id {
         ClientId::Individual(pesel) => {
             let result = sqlx::query!(
-                "SELECT id, price, product_id, start_date, end_date, years_supported, is_signed, is_paid, is_deleted 
+                "SELECT id, price, product_id, start_date, end_date, years_supported, status, renewed_from_id 
                  FROM contract 
-                 WHERE id = $1 AND personal_client_pesel = $2 AND is_deleted = FALSE",
+                 WHERE id = $1 AND personal_client_pesel = $2 AND status <> 'cancelled'",
                 contract_id,
                 pesel,
             )
@@ -243,18 +257,17 @@ pub async fn get_contract_by_id(
                     start_date: DateTime::from_naive_utc_and_offset(contract.start_date, Utc),
                     end_date: DateTime::from_naive_utc_and_offset(contract.end_date, Utc),
                     years_supported: contract.years_supported,
-                    is_signed: contract.is_signed,
-                    is_paid: contract.is_paid,
-                    is_deleted: contract.is_deleted,
+                    status: contract_status_from_db(&contract.status)?,
+                    renewed_from_id: contract.renewed_from_id,
                 }),
                 None => Err(sqlx::Error::RowNotFound),
             }
         }
         ClientId::Company(krs) => {
             let result = sqlx::query!(
-                "SELECT id, price, product_id, start_date, end_date, years_supported, is_signed, is_paid, is_deleted 
+                "SELECT id, price, product_id, start_date, end_date, years_supported, status, renewed_from_id 
                  FROM contract 
-                 WHERE id = $1 AND company_client_krs = $2 AND is_deleted = FALSE",
+                 WHERE id = $1 AND company_client_krs = $2 AND status <> 'cancelled'",
                 contract_id,
                 krs,
             )
@@ -272,9 +285,8 @@ pub async fn get_contract_by_id(
                     start_date: DateTime::from_naive_utc_and_offset(contract.start_date, Utc),
                     end_date: DateTime::from_naive_utc_and_offset(contract.end_date, Utc),
                     years_supported: contract.years_supported,
-                    is_signed: contract.is_signed,
-                    is_paid: contract.is_paid,
-                    is_deleted: contract.is_deleted,
+                    status: contract_status_from_db(&contract.status)?,
+                    renewed_from_id: contract.renewed_from_id,
                 }),
                 None => Err(sqlx::Error::RowNotFound),
             }
@@ -282,19 +294,22 @@ pub async fn get_contract_by_id(
     }
 }
 
-pub async fn pay_for_contract(
+fn contract_status_from_db(status: &str) -> Result<ContractStatus, sqlx::Error> {
+    ContractStatus::from_db(status)
+        .ok_or_else(|| sqlx::Error::Decode(format!("Unknown contract status: {}", status).into()))
+}
+
+pub async fn check_if_contract_exists(
     pool: &Pool<Postgres>,
     contract_id: i32,
-    _client_id: &ClientId,
-    amount: BigDecimal,
-) -> Result<(), AppError> {
-    match payments::create_payment_record_in_db(pool, contract_id, amount)
-        .await
-        .map_err(|e| AppError::InternalServerError(format!("Failed to create payment: {:?}", e)))
-    {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
-    }
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM contract WHERE id = $1 AND status <> 'cancelled')",
+    )
+    .bind(contract_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(result)
 }
 
 pub async fn get_payments_for_contract(
@@ -302,7 +317,7 @@ pub async fn get_payments_for_contract(
     contract_id: i32,
 ) -> Result<Vec<Payment>, AppError> {
     let result = sqlx::query!(
-        "SELECT id, contract_id, amount, payment_date, is_deleted FROM payment WHERE contract_id = $1",
+        "SELECT id, contract_id, amount, payment_date, is_deleted, reason, refunded_payment_id FROM payment WHERE contract_id = $1 ORDER BY payment_date, id",
         contract_id
     )
         .fetch_all(pool)
@@ -319,87 +334,2418 @@ pub async fn get_payments_for_contract(
             amount: p.amount,
             payment_date: DateTime::from_naive_utc_and_offset(p.payment_date, Utc),
             is_deleted: p.is_deleted,
+            reason: p.reason,
+            refunded_payment_id: p.refunded_payment_id,
         })
         .collect())
 }
 
-pub mod payments {
+pub mod contracts {
     use super::*;
-    use crate::db::get_payments_for_contract;
-    use bigdecimal::ToPrimitive;
+    use crate::client::{ContractBalance, ContractFilter};
+    use crate::handler::ValidationError;
+    use chrono::{Duration, NaiveDateTime};
 
-    pub async fn check_outstanding_payments(
-        pool: &Pool<Postgres>,
+    pub const EXPIRED_REFUND_REASON: &str = "Contract expired before it was paid";
+
+    // the offer has to stay open for at least 3 and at most 30 days
+    pub const MIN_SIGNING_WINDOW_DAYS: i64 = 3;
+    pub const MAX_SIGNING_WINDOW_DAYS: i64 = 30;
+
+    pub fn validate_signing_window(
+        start_date: &DateTime<Utc>,
+        end_date: &DateTime<Utc>,
+    ) -> Result<(), ValidationError> {
+        if end_date <= start_date {
+            return Err(ValidationError {
+                code: "end_date_not_after_start_date",
+                message: "Contract end date must be after its start date".to_string(),
+            });
+        }
+
+        let window = *end_date - *start_date;
+        if window < Duration::days(MIN_SIGNING_WINDOW_DAYS) {
+            return Err(ValidationError {
+                code: "signing_window_too_short",
+                message: format!(
+                    "Contract signing window must last at least {} days",
+                    MIN_SIGNING_WINDOW_DAYS
+                ),
+            });
+        }
+        if window > Duration::days(MAX_SIGNING_WINDOW_DAYS) {
+            return Err(ValidationError {
+                code: "signing_window_too_long",
+                message: format!(
+                    "Contract signing window can last at most {} days",
+                    MAX_SIGNING_WINDOW_DAYS
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    // Every allowed move between contract statuses, anything else is rejected
+    pub fn is_allowed_transition(from: ContractStatus, to: ContractStatus) -> bool {
+        use ContractStatus::*;
+        matches!(
+            (from, to),
+            (Draft, AwaitingPayment)
+                | (Draft, Signed)
+                | (Draft, Expired)
+                | (Draft, Cancelled)
+                | (AwaitingPayment, Signed)
+                | (AwaitingPayment, Expired)
+                | (AwaitingPayment, Cancelled)
+                | (Signed, AwaitingPayment)
+                | (Signed, Active)
+                | (Signed, Cancelled)
+                | (Signed, Refunded)
+                | (Active, AwaitingPayment)
+                | (Active, Cancelled)
+                | (Active, Refunded)
+        )
+    }
+
+    pub async fn record_initial_status(
+        conn: &mut PgConnection,
         contract_id: i32,
-    ) -> Result<BigDecimal, AppError> {
-        let payments = get_payments_for_contract(pool, contract_id)
+        status: ContractStatus,
+    ) -> Result<(), AppError> {
+        sqlx::query("INSERT INTO contract_status_history (contract_id, to_status) VALUES ($1, $2)")
+            .bind(contract_id)
+            .bind(status.as_str())
+            .execute(conn)
             .await
             .map_err(|e| {
-                AppError::InternalServerError(format!("Failed to get payments: {:?}", e))
+                AppError::InternalServerError(format!("Failed to record contract status: {:?}", e))
             })?;
+        Ok(())
+    }
+
+    fn status_error(e: sqlx::Error) -> AppError {
+        AppError::InternalServerError(format!("Failed to change contract status: {:?}", e))
+    }
+
+    // Locks the contract row until the caller's transaction ends, returns the current status.
+    // Status changes are booked today, so they are allowed on contracts dated in a closed
+    // accounting period, the payments they write are checked on their own.
+    pub async fn lock_contract(
+        conn: &mut PgConnection,
+        contract_id: i32,
+    ) -> Result<ContractStatus, AppError> {
+        let status =
+            sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1 FOR UPDATE")
+                .bind(contract_id)
+                .fetch_optional(&mut *conn)
+                .await
+                .map_err(|e| {
+                    AppError::InternalServerError(format!("Failed to lock contract: {:?}", e))
+                })?
+                .ok_or_else(|| AppError::BadRequest("Contract does not exist".to_string()))?;
+
+        ContractStatus::from_db(&status).ok_or_else(|| {
+            AppError::InternalServerError(format!("Unknown contract status: {}", status))
+        })
+    }
 
-        let outstanding_payments = payments.iter().map(|p| p.amount.clone()).sum();
+    // Moves the contract to `to` if the lifecycle allows it and records the change. Runs in
+    // the caller's transaction and keeps the contract row locked until it ends.
+    pub async fn change_status(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        to: ContractStatus,
+    ) -> Result<(), AppError> {
+        let from = lock_contract(conn, contract_id).await?;
+
+        if !is_allowed_transition(from, to) {
+            return Err(ValidationError {
+                code: "invalid_status_transition",
+                message: format!(
+                    "Contract cannot go from {} to {}",
+                    from.as_str(),
+                    to.as_str()
+                ),
+            }
+            .into());
+        }
 
-        Ok(outstanding_payments)
+        sqlx::query("UPDATE contract SET status = $1 WHERE id = $2")
+            .bind(to.as_str())
+            .bind(contract_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(status_error)?;
+        sqlx::query(
+            "INSERT INTO contract_status_history (contract_id, from_status, to_status) VALUES ($1, $2, $3)",
+        )
+        .bind(contract_id)
+        .bind(from.as_str())
+        .bind(to.as_str())
+        .execute(&mut *conn)
+        .await
+        .map_err(status_error)?;
+        Ok(())
     }
 
-    pub async fn create_payment_record_in_db(
+    // Unpaid contracts whose signing window has closed. A contract that was signed once stays
+    // signed, one that owes money again after a partial refund is not expired and refunded.
+    const EXPIRABLE: &str = "c.status IN ('draft', 'awaiting_payment')
+        AND c.end_date <= (NOW() AT TIME ZONE 'UTC')
+        AND NOT EXISTS (
+            SELECT 1 FROM contract_status_history h
+            WHERE h.contract_id = c.id AND h.to_status = 'signed'
+        )";
+
+    pub async fn find_expired_unpaid_contracts(
         pool: &Pool<Postgres>,
+    ) -> Result<Vec<i32>, AppError> {
+        sqlx::query_scalar::<_, i32>(&format!(
+            "SELECT c.id FROM contract c WHERE {EXPIRABLE} ORDER BY c.id"
+        ))
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to find expired contracts: {:?}", e))
+        })
+    }
+
+    // Locks an expired contract for the rest of the caller's transaction so it can be renewed,
+    // a contract is renewed at most once
+    pub async fn lock_for_renewal(
+        conn: &mut PgConnection,
         contract_id: i32,
-        amount: BigDecimal,
     ) -> Result<(), AppError> {
-        match sqlx::query!(
-            "INSERT INTO payment (contract_id, amount) VALUES ($1, $2)",
-            contract_id,
-            amount
+        let (status, renewed) = sqlx::query_as::<_, (String, bool)>(
+            "SELECT c.status, EXISTS(SELECT 1 FROM contract r WHERE r.renewed_from_id = c.id)
+             FROM contract c WHERE c.id = $1 FOR UPDATE",
         )
-        .execute(pool)
+        .bind(contract_id)
+        .fetch_optional(&mut *conn)
         .await
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(AppError::InternalServerError(format!(
-                "Failed to create payment: {:?}",
-                e
+        .map_err(|e| AppError::InternalServerError(format!("Failed to lock contract: {:?}", e)))?
+        .ok_or_else(|| AppError::BadRequest("Contract does not exist".to_string()))?;
+
+        if status != ContractStatus::Expired.as_str() {
+            return Err(AppError::BadRequest(format!(
+                "Only expired contracts can be renewed, this contract is {}",
+                status
+            )));
+        }
+        if renewed {
+            return Err(AppError::BadRequest(
+                "Contract has already been renewed".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Refunds whatever was paid towards an unpaid contract past its end date and marks it
+    // expired, in one transaction. Returns None when the contract doesn't qualify, e.g.
+    // because it was paid in the meantime or was signed before a partial refund.
+    pub async fn expire_contract(
+        pool: &Pool<Postgres>,
+        contract_id: i32,
+    ) -> Result<Option<BigDecimal>, AppError> {
+        let db_error = |e: sqlx::Error| {
+            AppError::InternalServerError(format!("Failed to expire contract: {:?}", e))
+        };
+        let mut tx = pool.begin().await.map_err(db_error)?;
+
+        lock_contract(&mut tx, contract_id).await?;
+        let still_expirable = sqlx::query_scalar::<_, bool>(&format!(
+            "SELECT {EXPIRABLE} FROM contract c WHERE c.id = $1"
+        ))
+        .bind(contract_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .unwrap_or(false);
+        if !still_expirable {
+            return Ok(None);
+        }
+
+        let paid = get_balance(&mut tx, contract_id).await?.net_paid();
+
+        let refunded = if paid > BigDecimal::from(0) {
+            payments::insert_refund(&mut tx, contract_id, &paid, EXPIRED_REFUND_REASON, None)
+                .await?;
+            paid
+        } else {
+            BigDecimal::from(0)
+        };
+
+        change_status(&mut tx, contract_id, ContractStatus::Expired).await?;
+        tx.commit().await.map_err(db_error)?;
+
+        Ok(Some(refunded))
+    }
+
+    // Cancels the contract and returns everything the client paid with a single compensating
+    // payment, in one transaction. Gives back the net amount that had been paid.
+    pub async fn cancel_contract(
+        pool: &Pool<Postgres>,
+        contract_id: i32,
+        reason: &str,
+    ) -> Result<BigDecimal, AppError> {
+        let db_error = |e: sqlx::Error| {
+            AppError::InternalServerError(format!("Failed to cancel contract: {:?}", e))
+        };
+        let mut tx = pool.begin().await.map_err(db_error)?;
+
+        change_status(&mut tx, contract_id, ContractStatus::Cancelled).await?;
+        let paid = get_balance(&mut tx, contract_id).await?.net_paid();
+        if paid > BigDecimal::from(0) {
+            payments::insert_refund(&mut tx, contract_id, &paid, reason, None).await?;
+        }
+        // everything is given back, so the revenue recognized so far is reversed today, earlier
+        // periods keep what they reported and the refund nets out the cash
+        let now = Utc::now();
+        revenue_schedule::stop_schedule_for_contract(&mut tx, contract_id, now).await?;
+        revenue_schedule::reverse_recognized_revenue(&mut tx, contract_id, now).await?;
+
+        tx.commit().await.map_err(db_error)?;
+        Ok(paid)
+    }
+
+    // Nothing is due once the contract is paid or closed, otherwise the price minus what was
+    // paid net of refunds
+    pub fn calculate_balance(
+        price: BigDecimal,
+        status: ContractStatus,
+        total_paid: BigDecimal,
+        total_refunded: BigDecimal,
+    ) -> ContractBalance {
+        let zero = BigDecimal::from(0);
+        let remaining_due = match status {
+            ContractStatus::Draft | ContractStatus::AwaitingPayment => {
+                let remaining = &price - (&total_paid - &total_refunded);
+                if remaining > zero {
+                    remaining
+                } else {
+                    zero
+                }
+            }
+            _ => zero,
+        };
+        ContractBalance {
+            price,
+            total_paid,
+            total_refunded,
+            remaining_due,
+        }
+    }
+
+    // Balance from payments that are already loaded, deleted payments don't count
+    pub fn balance_from_payments(contract: &Contract, payments: &[Payment]) -> ContractBalance {
+        let zero = BigDecimal::from(0);
+        let amounts = payments
+            .iter()
+            .filter(|payment| !payment.is_deleted)
+            .map(|payment| &payment.amount);
+        let total_paid: BigDecimal = amounts.clone().filter(|amount| **amount > zero).sum();
+        let total_refunded: BigDecimal = amounts
+            .filter(|amount| **amount < zero)
+            .map(|amount| -amount)
+            .sum();
+        calculate_balance(
+            contract.price.clone(),
+            contract.status,
+            total_paid,
+            total_refunded,
+        )
+    }
+
+    pub async fn get_balance(
+        conn: &mut PgConnection,
+        contract_id: i32,
+    ) -> Result<ContractBalance, AppError> {
+        let db_error = |e: sqlx::Error| {
+            AppError::InternalServerError(format!("Failed to get contract balance: {:?}", e))
+        };
+        let (price, status, total_paid, total_refunded) =
+            sqlx::query_as::<_, (BigDecimal, String, BigDecimal, BigDecimal)>(
+                "SELECT c.price, c.status,
+                        COALESCE(SUM(p.amount) FILTER (WHERE p.amount > 0), 0),
+                        COALESCE(-SUM(p.amount) FILTER (WHERE p.amount < 0), 0)
+                 FROM contract c
+                 LEFT JOIN payment p ON p.contract_id = c.id AND p.is_deleted = FALSE
+                 WHERE c.id = $1
+                 GROUP BY c.id",
+            )
+            .bind(contract_id)
+            .fetch_optional(conn)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| AppError::BadRequest("Contract does not exist".to_string()))?;
+        let status = ContractStatus::from_db(&status).ok_or_else(|| {
+            AppError::InternalServerError(format!("Unknown contract status: {}", status))
+        })?;
+        Ok(calculate_balance(price, status, total_paid, total_refunded))
+    }
+
+    type ContractRow = (
+        i32,
+        BigDecimal,
+        Option<i32>,
+        Option<String>,
+        Option<String>,
+        NaiveDateTime,
+        NaiveDateTime,
+        i32,
+        String,
+        Option<i32>,
+    );
+
+    const CONTRACT_COLUMNS: &str = "id, price, product_id, personal_client_pesel, company_client_krs, start_date, end_date, years_supported, status, renewed_from_id";
+
+    // client, status and start date range, shared by the contract list and its count
+    const CONTRACT_FILTER: &str = "($1::TEXT IS NULL OR personal_client_pesel = $1)
+          AND ($2::TEXT IS NULL OR company_client_krs = $2)
+          AND ($3::TEXT IS NULL OR status = $3)
+          AND ($4::DATE IS NULL OR start_date >= $4)
+          AND ($5::DATE IS NULL OR start_date < $5 + 1)";
+
+    // A contract belongs to either an individual or a company client
+    pub fn client_id_from_row(
+        contract_id: i32,
+        pesel: Option<String>,
+        krs: Option<String>,
+    ) -> Result<ClientId, AppError> {
+        match (pesel, krs) {
+            (Some(pesel), None) => Ok(ClientId::Individual(pesel)),
+            (None, Some(krs)) => Ok(ClientId::Company(krs)),
+            _ => Err(AppError::InternalServerError(format!(
+                "Contract {} has no client",
+                contract_id
             ))),
         }
     }
 
-    pub async fn handle_full_payment(
+    fn contract_from_row(row: ContractRow) -> Result<Contract, AppError> {
+        let (
+            id,
+            price,
+            product_id,
+            pesel,
+            krs,
+            start_date,
+            end_date,
+            years_supported,
+            status,
+            renewed_from_id,
+        ) = row;
+        let client_id = client_id_from_row(id, pesel, krs)?;
+        Ok(Contract {
+            id,
+            price,
+            product_id: product_id.ok_or_else(|| {
+                AppError::InternalServerError(format!("Contract {} has no product", id))
+            })?,
+            client_id,
+            start_date: DateTime::from_naive_utc_and_offset(start_date, Utc),
+            end_date: DateTime::from_naive_utc_and_offset(end_date, Utc),
+            years_supported,
+            status: ContractStatus::from_db(&status).ok_or_else(|| {
+                AppError::InternalServerError(format!("Unknown contract status: {}", status))
+            })?,
+            renewed_from_id,
+        })
+    }
+
+    // Looks a contract up regardless of its client or status
+    pub async fn get_contract(
         pool: &Pool<Postgres>,
         contract_id: i32,
-        client_id: ClientId,
-    ) -> Result<(), AppError> {
-        match client_id {
-            ClientId::Individual(pesel) => {
-                match sqlx::query!(
-                    "UPDATE contract SET is_paid = TRUE WHERE id = $1 AND personal_client_pesel = $2",
-                    contract_id,
-                    pesel
-                )
-                .execute(pool)
+    ) -> Result<Option<Contract>, AppError> {
+        let row = sqlx::query_as::<_, ContractRow>(&format!(
+            "SELECT {} FROM contract WHERE id = $1",
+            CONTRACT_COLUMNS
+        ))
+        .bind(contract_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError::InternalServerError(format!("Failed to get contract: {:?}", e)))?;
+
+        row.map(contract_from_row).transpose()
+    }
+
+    // One page of the client's contracts, newest first, with the total number of matches
+    pub async fn list_contracts_for_client(
+        pool: &Pool<Postgres>,
+        client_id: &ClientId,
+        filter: &ContractFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<Contract>, i64), AppError> {
+        let (pesel, krs) = match client_id {
+            ClientId::Individual(pesel) => (Some(pesel), None),
+            ClientId::Company(krs) => (None, Some(krs)),
+        };
+        let status = filter.status.map(|status| status.as_str());
+
+        let total = sqlx::query_scalar::<_, i64>(&format!(
+            "SELECT COUNT(*) FROM contract WHERE {}",
+            CONTRACT_FILTER
+        ))
+        .bind(pesel)
+        .bind(krs)
+        .bind(status)
+        .bind(filter.from)
+        .bind(filter.to)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to count contracts: {:?}", e))
+        })?;
+
+        let rows = sqlx::query_as::<_, ContractRow>(&format!(
+            "SELECT {} FROM contract WHERE {} ORDER BY start_date DESC, id DESC LIMIT $6 OFFSET $7",
+            CONTRACT_COLUMNS, CONTRACT_FILTER
+        ))
+        .bind(pesel)
+        .bind(krs)
+        .bind(status)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::InternalServerError(format!("Failed to list contracts: {:?}", e)))?;
+
+        let contracts = rows
+            .into_iter()
+            .map(contract_from_row)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((contracts, total))
+    }
+}
+
+pub mod pricing {
+    use super::*;
+    use crate::handler::ValidationError;
+    use bigdecimal::RoundingMode;
+
+    // Prices a contract: the support surcharge is added to the product price first and
+    // both discounts are then taken from that list price
+    pub fn calculate_price(
+        base_price: &BigDecimal,
+        years_supported: i32,
+        product_discount_rate: &BigDecimal,
+        returning_customer_discount_rate: &BigDecimal,
+    ) -> PriceBreakdown {
+        let support_price = BigDecimal::from(years_supported * obligations::SUPPORT_PRICE_PER_YEAR);
+        let list_price = base_price + &support_price;
+        let product_discount =
+            (&list_price * product_discount_rate).with_scale_round(2, RoundingMode::HalfUp);
+        let returning_customer_discount = (&list_price * returning_customer_discount_rate)
+            .with_scale_round(2, RoundingMode::HalfUp);
+        let final_price = (&list_price - &product_discount - &returning_customer_discount)
+            .with_scale_round(2, RoundingMode::HalfUp);
+
+        PriceBreakdown {
+            base_price: base_price.clone(),
+            years_supported,
+            support_price,
+            list_price,
+            product_discount_rate: product_discount_rate.clone(),
+            product_discount,
+            returning_customer_discount_rate: returning_customer_discount_rate.clone(),
+            returning_customer_discount,
+            final_price,
+        }
+    }
+
+    pub fn validate_years_supported(
+        years_supported: i32,
+        min_years_supported: i32,
+        max_years_supported: i32,
+    ) -> Result<(), ValidationError> {
+        if years_supported < min_years_supported || years_supported > max_years_supported {
+            return Err(ValidationError {
+                code: "years_supported_out_of_range",
+                message: format!(
+                    "Support for this product can be bought for {} to {} years",
+                    min_years_supported, max_years_supported
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn get_support_year_limits(
+        pool: &Pool<Postgres>,
+        product_id: i32,
+    ) -> Result<(i32, i32), AppError> {
+        sqlx::query_as::<_, (i32, i32)>(
+            "SELECT min_years_supported, max_years_supported FROM software WHERE id = $1",
+        )
+        .bind(product_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to get support year limits: {:?}", e))
+        })
+    }
+
+    // Validates the support period and prices the product for the client at today's prices
+    pub async fn price_contract(
+        pool: &Pool<Postgres>,
+        product_id: i32,
+        years_supported: i32,
+        client_id: &ClientId,
+    ) -> Result<PriceBreakdown, AppError> {
+        let (min_years_supported, max_years_supported) =
+            get_support_year_limits(pool, product_id).await?;
+        validate_years_supported(years_supported, min_years_supported, max_years_supported)?;
+
+        let base_price = get_price_for_product(pool, product_id)
+            .await
+            .map_err(|e| AppError::InternalServerError(format!("Failed to get price: {:?}", e)))?;
+        let (product_discount_rate, returning_customer_discount_rate) =
+            find_discount_rates_for_client(pool, product_id, client_id.clone())
                 .await
                 .map_err(|e| {
-                    AppError::InternalServerError(format!("Failed to handle full payment: {:?}", e))
-                }) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e),
+                    AppError::InternalServerError(format!("Failed to get discount: {}", e))
+                })?;
+
+        Ok(calculate_price(
+            &base_price,
+            years_supported,
+            &product_discount_rate,
+            &returning_customer_discount_rate,
+        ))
+    }
+}
+
+pub mod payments {
+    use super::*;
+    use crate::client::{PaymentRefund, SubscriptionPaymentKind};
+    use crate::handler::ValidationError;
+
+    fn payment_error(e: sqlx::Error) -> AppError {
+        AppError::InternalServerError(format!("Failed to create payment: {:?}", e))
+    }
+
+    // Locks the contract row until the transaction ends so concurrent payments for the
+    // same contract are applied one after another, returns its status
+    async fn lock_payable_contract(
+        conn: &mut PgConnection,
+        contract_id: i32,
+    ) -> Result<ContractStatus, AppError> {
+        let status = contracts::lock_contract(conn, contract_id).await?;
+        match status {
+            ContractStatus::Draft | ContractStatus::AwaitingPayment => Ok(status),
+            ContractStatus::Signed | ContractStatus::Active => {
+                Err(AppError::BadRequest("Contract is already paid".to_string()))
+            }
+            _ => Err(AppError::BadRequest(format!(
+                "Contract is {} and cannot be paid",
+                status.as_str()
+            ))),
+        }
+    }
+
+    async fn insert_payment(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        amount: &BigDecimal,
+    ) -> Result<i32, AppError> {
+        let payment_date = Utc::now();
+        periods::check_period_is_open(conn, &payment_date).await?;
+        sqlx::query_scalar!(
+            "INSERT INTO payment (contract_id, amount, payment_date) VALUES ($1, $2, $3) RETURNING id",
+            contract_id,
+            amount,
+            payment_date.naive_utc()
+        )
+        .fetch_one(conn)
+        .await
+        .map_err(payment_error)
+    }
+
+    // Records an installment, the contract is signed once the installments cover its price
+    pub async fn pay_installment(
+        pool: &Pool<Postgres>,
+        contract_id: i32,
+        amount: &BigDecimal,
+    ) -> Result<(), AppError> {
+        let mut tx = pool.begin().await.map_err(payment_error)?;
+        let status = lock_payable_contract(&mut tx, contract_id).await?;
+
+        if amount <= &BigDecimal::from(0) {
+            return Err(AppError::BadRequest(
+                "Amount must be greater than zero".to_string(),
+            ));
+        }
+        let balance = contracts::get_balance(&mut tx, contract_id).await?;
+        if amount > &balance.remaining_due {
+            return Err(AppError::BadRequest(format!(
+                "Amount is greater than the remaining due of {}",
+                balance.remaining_due
+            )));
+        }
+
+        // contracts with a plan pay the installments in order. An installment reopened by a
+        // partial refund may be more than what is left, then only the rest is due.
+        let installment = installments::next_due(&mut tx, contract_id).await?;
+        if let Some(installment) = &installment {
+            let installment_due = if installment.amount > balance.remaining_due {
+                &balance.remaining_due
+            } else {
+                &installment.amount
+            };
+            if amount != installment_due {
+                return Err(ValidationError {
+                    code: "installment_amount_mismatch",
+                    message: format!(
+                        "Installment {} of {} is due on {}",
+                        installment.installment_number,
+                        installment_due,
+                        installment.due_date.date_naive()
+                    ),
                 }
+                .into());
             }
-            ClientId::Company(krs) => {
-                match sqlx::query!(
-                    "UPDATE contract SET is_paid = TRUE WHERE id = $1 AND company_client_krs = $2",
-                    contract_id,
-                    krs
-                )
-                .execute(pool)
-                .await
-                .map_err(|e| {
-                    AppError::InternalServerError(format!("Failed to handle full payment: {:?}", e))
-                }) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e),
+        }
+
+        let payment_id = insert_payment(&mut tx, contract_id, amount).await?;
+        if let Some(installment) = installment {
+            installments::mark_paid(
+                &mut tx,
+                contract_id,
+                installment.installment_number,
+                payment_id,
+            )
+            .await?;
+        }
+
+        if amount == &balance.remaining_due {
+            handle_full_payment(&mut tx, contract_id).await?;
+        } else if status == ContractStatus::Draft {
+            contracts::change_status(&mut tx, contract_id, ContractStatus::AwaitingPayment).await?;
+        }
+
+        tx.commit().await.map_err(payment_error)
+    }
+
+    // Records a payment of everything that is left to pay and signs the contract
+    pub async fn pay_in_full(
+        pool: &Pool<Postgres>,
+        contract_id: i32,
+        amount: &BigDecimal,
+    ) -> Result<(), AppError> {
+        let mut tx = pool.begin().await.map_err(payment_error)?;
+        lock_payable_contract(&mut tx, contract_id).await?;
+
+        let balance = contracts::get_balance(&mut tx, contract_id).await?;
+        if amount != &balance.remaining_due {
+            return Err(AppError::BadRequest(format!(
+                "Amount does not match the remaining due of {}",
+                balance.remaining_due
+            )));
+        }
+
+        let payment_id = insert_payment(&mut tx, contract_id, amount).await?;
+        installments::mark_all_paid(&mut tx, contract_id, payment_id).await?;
+        handle_full_payment(&mut tx, contract_id).await?;
+
+        tx.commit().await.map_err(payment_error)
+    }
+
+    // Writes the compensating negative payment, `amount` is the positive sum returned
+    pub async fn insert_refund(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        amount: &BigDecimal,
+        reason: &str,
+        refunded_payment_id: Option<i32>,
+    ) -> Result<Payment, AppError> {
+        // the refund is a new payment booked today, the refunded one stays untouched
+        let payment_date = Utc::now();
+        periods::check_period_is_open(conn, &payment_date).await?;
+        let (id, amount) = sqlx::query_as::<_, (i32, BigDecimal)>(
+            "INSERT INTO payment (contract_id, amount, reason, refunded_payment_id, payment_date)
+                 VALUES ($1, $2, $3, $4, $5)
+                 RETURNING id, amount",
+        )
+        .bind(contract_id)
+        .bind(-amount)
+        .bind(reason)
+        .bind(refunded_payment_id)
+        .bind(payment_date.naive_utc())
+        .fetch_one(conn)
+        .await
+        .map_err(|e| AppError::InternalServerError(format!("Failed to create refund: {:?}", e)))?;
+        Ok(Payment {
+            id,
+            contract_id,
+            amount,
+            payment_date,
+            is_deleted: false,
+            reason: Some(reason.to_string()),
+            refunded_payment_id,
+        })
+    }
+
+    // Contract the payment was made for, None for unknown, deleted or subscription payments
+    pub async fn get_payment_contract_id(
+        pool: &Pool<Postgres>,
+        payment_id: i32,
+    ) -> Result<Option<i32>, AppError> {
+        sqlx::query_scalar::<_, Option<i32>>(
+            "SELECT contract_id FROM payment WHERE id = $1 AND is_deleted = FALSE",
+        )
+        .bind(payment_id)
+        .fetch_optional(pool)
+        .await
+        .map(Option::flatten)
+        .map_err(|e| AppError::InternalServerError(format!("Failed to get payment: {:?}", e)))
+    }
+
+    // Gives back part or all of a payment, `amount` defaults to whatever of the payment has
+    // not been refunded yet. A paid contract goes back to awaiting payment, or to refunded
+    // once nothing is left paid, and stops recognizing its revenue from today on. A full
+    // refund also reverses the revenue recognized so far.
+    pub async fn refund_payment(
+        pool: &Pool<Postgres>,
+        payment_id: i32,
+        amount: Option<&BigDecimal>,
+        reason: &str,
+    ) -> Result<PaymentRefund, AppError> {
+        let db_error = |e: sqlx::Error| {
+            AppError::InternalServerError(format!("Failed to refund payment: {:?}", e))
+        };
+        let mut tx = pool.begin().await.map_err(db_error)?;
+
+        let contract_id = sqlx::query_scalar::<_, Option<i32>>(
+            "SELECT contract_id FROM payment WHERE id = $1 AND is_deleted = FALSE",
+        )
+        .bind(payment_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .flatten()
+        .ok_or_else(|| AppError::BadRequest("Payment does not exist".to_string()))?;
+
+        // same lock as payments take, so refunds and payments of a contract don't interleave
+        let status = contracts::lock_contract(&mut tx, contract_id).await?;
+
+        let (paid, already_refunded, is_refund) =
+            sqlx::query_as::<_, (BigDecimal, BigDecimal, bool)>(
+                "SELECT p.amount, COALESCE(-SUM(r.amount), 0), p.refunded_payment_id IS NOT NULL
+                 FROM payment p
+                 LEFT JOIN payment r ON r.refunded_payment_id = p.id AND r.is_deleted = FALSE
+                 WHERE p.id = $1
+                 GROUP BY p.id",
+            )
+            .bind(payment_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        let zero = BigDecimal::from(0);
+        if is_refund || paid <= zero {
+            return Err(AppError::BadRequest(
+                "Refunds cannot be refunded".to_string(),
+            ));
+        }
+
+        // never more than is left of this payment, nor more than the contract holds after
+        // earlier refunds such as the one made on cancellation
+        let balance = contracts::get_balance(&mut tx, contract_id).await?;
+        let left_of_payment = &paid - &already_refunded;
+        let net_paid = balance.net_paid();
+        let refundable = if left_of_payment < net_paid {
+            left_of_payment
+        } else {
+            net_paid.clone()
+        };
+        if refundable <= zero {
+            return Err(AppError::BadRequest(
+                "Payment has already been refunded".to_string(),
+            ));
+        }
+        let amount = amount.cloned().unwrap_or_else(|| refundable.clone());
+        if amount <= zero {
+            return Err(AppError::BadRequest(
+                "Amount must be greater than zero".to_string(),
+            ));
+        }
+        if amount > refundable {
+            return Err(AppError::BadRequest(format!(
+                "Amount is greater than the {} that can still be refunded",
+                refundable
+            )));
+        }
+
+        let refund = insert_refund(&mut tx, contract_id, &amount, reason, Some(payment_id)).await?;
+        // as much of what the payment settled as the refund makes owed again
+        let owed = &balance.price - (&net_paid - &amount);
+        installments::reopen_paid_by(&mut tx, contract_id, payment_id, &owed).await?;
+
+        let status = match status {
+            ContractStatus::Signed | ContractStatus::Active => {
+                let to = if net_paid == amount {
+                    ContractStatus::Refunded
+                } else {
+                    ContractStatus::AwaitingPayment
+                };
+                // a partial refund keeps what was recognized, a full one reverses it today
+                let now = Utc::now();
+                revenue_schedule::stop_schedule_for_contract(&mut tx, contract_id, now).await?;
+                if to == ContractStatus::Refunded {
+                    revenue_schedule::reverse_recognized_revenue(&mut tx, contract_id, now).await?;
                 }
+                contracts::change_status(&mut tx, contract_id, to).await?;
+                to
+            }
+            status => status,
+        };
+        let balance = contracts::get_balance(&mut tx, contract_id).await?;
+
+        tx.commit().await.map_err(db_error)?;
+        Ok(PaymentRefund {
+            refund,
+            contract_status: status,
+            balance,
+        })
+    }
+
+    pub async fn create_subscription_payment_record_in_db(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+        period_number: i32,
+        kind: SubscriptionPaymentKind,
+        amount: BigDecimal,
+    ) -> Result<i32, AppError> {
+        let payment_date = Utc::now();
+        periods::check_period_is_open(conn, &payment_date).await?;
+        sqlx::query_scalar::<_, i32>(
+            "INSERT INTO payment (subscription_id, period_number, subscription_payment_kind, amount, payment_date) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(subscription_id)
+        .bind(period_number)
+        .bind(kind.as_str())
+        .bind(amount)
+        .bind(payment_date.naive_utc())
+        .fetch_one(conn)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(db_error) if db_error.is_unique_violation() => {
+                AppError::BadRequest("This renewal period has already been paid".to_string())
+            }
+            _ => AppError::InternalServerError(format!("Failed to create payment: {:?}", e)),
+        })
+    }
+
+    async fn handle_full_payment(
+        conn: &mut PgConnection,
+        contract_id: i32,
+    ) -> Result<(), AppError> {
+        // paying the whole price within the signing window signs the contract
+        contracts::change_status(conn, contract_id, ContractStatus::Signed).await?;
+
+        // the contract is paid, so its revenue can start being recognized
+        revenue_schedule::create_schedule_for_contract(conn, contract_id, Utc::now()).await?;
+        contracts::change_status(conn, contract_id, ContractStatus::Active).await
+    }
+}
+
+pub mod installments {
+    use super::*;
+    use crate::client::{Installment, InstallmentPlanEntry, OverdueInstallment};
+    use crate::handler::ValidationError;
+
+    // The plan has to add up to the contract price and be paid off in order within the
+    // contract's signing window
+    pub fn validate_plan(
+        plan: &[InstallmentPlanEntry],
+        price: &BigDecimal,
+        start_date: &DateTime<Utc>,
+        end_date: &DateTime<Utc>,
+    ) -> Result<(), ValidationError> {
+        if plan.is_empty() {
+            return Err(ValidationError {
+                code: "installment_plan_empty",
+                message: "An installment plan needs at least one installment".to_string(),
+            });
+        }
+
+        let mut previous_due_date: Option<&DateTime<Utc>> = None;
+        for (number, installment) in (1..).zip(plan) {
+            if installment.amount <= BigDecimal::from(0) {
+                return Err(ValidationError {
+                    code: "installment_amount_not_positive",
+                    message: format!("Installment {} must be greater than zero", number),
+                });
             }
+            // stored as NUMERIC(10, 2), anything finer would be rounded away
+            if installment.amount.with_scale(2) != installment.amount {
+                return Err(ValidationError {
+                    code: "installment_amount_too_precise",
+                    message: format!(
+                        "Installment {} can't have more than 2 decimal places",
+                        number
+                    ),
+                });
+            }
+            if installment.due_date < *start_date || installment.due_date > *end_date {
+                return Err(ValidationError {
+                    code: "installment_due_date_out_of_window",
+                    message: format!(
+                        "Installment {} must be due between the contract start and end dates",
+                        number
+                    ),
+                });
+            }
+            if previous_due_date.is_some_and(|previous| installment.due_date <= *previous) {
+                return Err(ValidationError {
+                    code: "installment_due_dates_not_ascending",
+                    message: format!(
+                        "Installment {} must be due after installment {}",
+                        number,
+                        number - 1
+                    ),
+                });
+            }
+            previous_due_date = Some(&installment.due_date);
         }
+
+        let total: BigDecimal = plan.iter().map(|installment| &installment.amount).sum();
+        if &total != price {
+            return Err(ValidationError {
+                code: "installment_plan_total_mismatch",
+                message: format!(
+                    "Installments add up to {} but the contract price is {}",
+                    total, price
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    fn installment_error(e: sqlx::Error) -> AppError {
+        AppError::InternalServerError(format!("Failed to access installments: {:?}", e))
+    }
+
+    // Runs in the transaction that creates the contract
+    pub async fn create_plan(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        plan: &[InstallmentPlanEntry],
+    ) -> Result<(), AppError> {
+        for (number, installment) in (1..).zip(plan) {
+            sqlx::query(
+                "INSERT INTO installment_schedule (contract_id, installment_number, amount, due_date) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(contract_id)
+            .bind(number)
+            .bind(&installment.amount)
+            .bind(installment.due_date.naive_utc())
+            .execute(&mut *conn)
+            .await
+            .map_err(installment_error)?;
+        }
+        Ok(())
+    }
+
+    type InstallmentRow = (
+        i32,
+        BigDecimal,
+        chrono::NaiveDateTime,
+        Option<i32>,
+        Option<chrono::NaiveDateTime>,
+    );
+
+    fn installment_from_row(row: InstallmentRow) -> Installment {
+        let (installment_number, amount, due_date, payment_id, paid_at) = row;
+        Installment {
+            installment_number,
+            amount,
+            due_date: DateTime::from_naive_utc_and_offset(due_date, Utc),
+            payment_id,
+            paid_at: paid_at.map(|paid_at| DateTime::from_naive_utc_and_offset(paid_at, Utc)),
+        }
+    }
+
+    const INSTALLMENT_COLUMNS: &str =
+        "i.installment_number, i.amount, i.due_date, i.payment_id, p.payment_date";
+
+    pub async fn get_installments_for_contract(
+        pool: &Pool<Postgres>,
+        contract_id: i32,
+    ) -> Result<Vec<Installment>, AppError> {
+        let rows = sqlx::query_as::<_, InstallmentRow>(&format!(
+            "SELECT {INSTALLMENT_COLUMNS}
+             FROM installment_schedule i
+             LEFT JOIN payment p ON p.id = i.payment_id
+             WHERE i.contract_id = $1
+             ORDER BY i.installment_number"
+        ))
+        .bind(contract_id)
+        .fetch_all(pool)
+        .await
+        .map_err(installment_error)?;
+        Ok(rows.into_iter().map(installment_from_row).collect())
+    }
+
+    // First installment that hasn't been paid yet, None without a plan or once it is paid off.
+    // Callers hold the contract lock, see db::payments.
+    pub async fn next_due(
+        conn: &mut PgConnection,
+        contract_id: i32,
+    ) -> Result<Option<Installment>, AppError> {
+        let row = sqlx::query_as::<_, InstallmentRow>(&format!(
+            "SELECT {INSTALLMENT_COLUMNS}
+             FROM installment_schedule i
+             LEFT JOIN payment p ON p.id = i.payment_id
+             WHERE i.contract_id = $1 AND i.payment_id IS NULL
+             ORDER BY i.installment_number
+             LIMIT 1"
+        ))
+        .bind(contract_id)
+        .fetch_optional(conn)
+        .await
+        .map_err(installment_error)?;
+        Ok(row.map(installment_from_row))
+    }
+
+    pub async fn mark_paid(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        installment_number: i32,
+        payment_id: i32,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE installment_schedule SET payment_id = $3 WHERE contract_id = $1 AND installment_number = $2",
+        )
+        .bind(contract_id)
+        .bind(installment_number)
+        .bind(payment_id)
+        .execute(conn)
+        .await
+        .map_err(installment_error)?;
+        Ok(())
+    }
+
+    // Reopens installments settled by a refunded payment, latest first, until the open
+    // installments cover the `owed` amount left to pay after the refund
+    pub async fn reopen_paid_by(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        payment_id: i32,
+        owed: &BigDecimal,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE installment_schedule i SET payment_id = NULL
+             FROM (
+                SELECT installment_number,
+                       COALESCE(SUM(amount) OVER (
+                           ORDER BY installment_number DESC
+                           ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+                       ), 0) AS reopened_before
+                FROM installment_schedule
+                WHERE contract_id = $1 AND payment_id = $2
+             ) settled
+             WHERE i.contract_id = $1
+               AND i.installment_number = settled.installment_number
+               AND settled.reopened_before + COALESCE((
+                    SELECT SUM(amount) FROM installment_schedule
+                    WHERE contract_id = $1 AND payment_id IS NULL
+                ), 0) < $3",
+        )
+        .bind(contract_id)
+        .bind(payment_id)
+        .bind(owed)
+        .execute(conn)
+        .await
+        .map_err(installment_error)?;
+        Ok(())
+    }
+
+    // A single payment of everything that is left settles the rest of the plan
+    pub async fn mark_all_paid(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        payment_id: i32,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE installment_schedule SET payment_id = $2 WHERE contract_id = $1 AND payment_id IS NULL",
+        )
+        .bind(contract_id)
+        .bind(payment_id)
+        .execute(conn)
+        .await
+        .map_err(installment_error)?;
+        Ok(())
+    }
+
+    // Unpaid installments past their due date on contracts that are still waiting for payment.
+    // Like db::payments::pay_installment, an installment only counts for what is left to pay.
+    pub async fn list_overdue(
+        pool: &Pool<Postgres>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<OverdueInstallment>, AppError> {
+        let rows = sqlx::query_as::<
+            _,
+            (
+                i32,
+                Option<String>,
+                Option<String>,
+                i32,
+                BigDecimal,
+                chrono::NaiveDateTime,
+            ),
+        >(
+            "SELECT id, personal_client_pesel, company_client_krs, installment_number, due, due_date
+             FROM (
+                SELECT c.id, c.personal_client_pesel, c.company_client_krs,
+                       i.installment_number, i.due_date,
+                       LEAST(
+                           i.amount,
+                           c.price
+                               - COALESCE((
+                                   SELECT SUM(p.amount) FROM payment p
+                                   WHERE p.contract_id = c.id AND p.is_deleted = FALSE
+                               ), 0)
+                               - COALESCE(SUM(i.amount) OVER (
+                                   PARTITION BY c.id ORDER BY i.installment_number
+                                   ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+                               ), 0)
+                       ) AS due
+                FROM installment_schedule i
+                JOIN contract c ON c.id = i.contract_id
+                WHERE i.payment_id IS NULL
+                  AND c.status IN ('draft', 'awaiting_payment')
+             ) open
+             WHERE due_date < $1 AND due > 0
+             ORDER BY due_date, id, installment_number",
+        )
+        .bind(now.naive_utc())
+        .fetch_all(pool)
+        .await
+        .map_err(installment_error)?;
+
+        rows.into_iter()
+            .map(
+                |(contract_id, pesel, krs, installment_number, amount, due_date)| {
+                    let due_date = DateTime::from_naive_utc_and_offset(due_date, Utc);
+                    Ok(OverdueInstallment {
+                        contract_id,
+                        client_id: contracts::client_id_from_row(contract_id, pesel, krs)?,
+                        installment_number,
+                        amount,
+                        due_date,
+                        days_overdue: (now - due_date).num_days(),
+                    })
+                },
+            )
+            .collect()
+    }
+}
+
+pub mod revenue {
+    use super::*;
+    use crate::client::{Granularity, RevenueFilter};
+    use chrono::{Datelike, Months, NaiveDate};
+
+    // Recognized revenue is whatever the revenue schedule has recognized so far, every report
+    // takes it from there. Entries are only written once a contract is paid, see
+    // db::revenue_schedule.
+    pub async fn calculate_current_revenue(
+        pool: &Pool<Postgres>,
+        filter: &RevenueFilter,
+    ) -> Result<BigDecimal, AppError> {
+        let (pesel, krs) = filter.client_keys();
+        sqlx::query_scalar::<_, BigDecimal>(
+            "SELECT COALESCE(SUM(r.amount), 0)
+             FROM revenue_schedule r
+             JOIN contract c ON c.id = r.contract_id
+             WHERE r.is_deleted = FALSE AND r.recognition_date <= (NOW() AT TIME ZONE 'UTC')
+               AND ($1::INTEGER IS NULL OR c.product_id = $1)
+               AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+               AND ($3::TEXT IS NULL OR c.company_client_krs = $3)",
+        )
+        .bind(filter.product_id)
+        .bind(pesel)
+        .bind(krs)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to calculate current revenue: {:?}", e))
+        })
+    }
+
+    // Unpaid contracts are expected to bring in their full contract price, paid ones whatever
+    // their schedule still has to recognize
+    pub async fn calculate_pending_revenue(
+        pool: &Pool<Postgres>,
+        filter: &RevenueFilter,
+    ) -> Result<BigDecimal, AppError> {
+        let (pesel, krs) = filter.client_keys();
+        sqlx::query_scalar::<_, BigDecimal>(
+            "SELECT COALESCE((
+                    SELECT SUM(c.price)
+                    FROM contract c
+                    WHERE c.status IN ('draft', 'awaiting_payment') AND c.end_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)
+                + COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    JOIN contract c ON c.id = r.contract_id
+                    WHERE r.is_deleted = FALSE AND r.recognition_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)",
+        )
+        .bind(filter.product_id)
+        .bind(pesel)
+        .bind(krs)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to calculate pending revenue: {:?}", e))
+        })
+    }
+
+    // Returns (product_id, product name, recognized, pending) for every product in the catalog
+    pub async fn calculate_revenue_by_product(
+        pool: &Pool<Postgres>,
+        filter: &RevenueFilter,
+    ) -> Result<Vec<(i32, String, BigDecimal, BigDecimal)>, AppError> {
+        let (pesel, krs) = filter.client_keys();
+        sqlx::query_as::<_, (i32, String, BigDecimal, BigDecimal)>(
+            "SELECT s.id, s.name,
+                COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    JOIN contract c ON c.id = r.contract_id
+                    WHERE c.product_id = s.id AND r.is_deleted = FALSE
+                      AND r.recognition_date <= (NOW() AT TIME ZONE 'UTC')
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0),
+                COALESCE((
+                    SELECT SUM(c.price)
+                    FROM contract c
+                    WHERE c.product_id = s.id AND c.status IN ('draft', 'awaiting_payment') AND c.end_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)
+                + COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    JOIN contract c ON c.id = r.contract_id
+                    WHERE c.product_id = s.id AND r.is_deleted = FALSE
+                      AND r.recognition_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)
+             FROM software s
+             WHERE $1::INTEGER IS NULL OR s.id = $1
+             ORDER BY s.id",
+        )
+        .bind(filter.product_id)
+        .bind(pesel)
+        .bind(krs)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!(
+                "Failed to calculate revenue by product: {:?}",
+                e
+            ))
+        })
+    }
+
+    // Returns (contract_type, recognized, pending) for both client segments
+    pub async fn calculate_revenue_by_segment(
+        pool: &Pool<Postgres>,
+        filter: &RevenueFilter,
+    ) -> Result<Vec<(String, BigDecimal, BigDecimal)>, AppError> {
+        let (pesel, krs) = filter.client_keys();
+        sqlx::query_as::<_, (String, BigDecimal, BigDecimal)>(
+            "SELECT segment.contract_type,
+                COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    JOIN contract c ON c.id = r.contract_id
+                    WHERE c.contract_type = segment.contract_type AND r.is_deleted = FALSE
+                      AND r.recognition_date <= (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0),
+                COALESCE((
+                    SELECT SUM(c.price)
+                    FROM contract c
+                    WHERE c.contract_type = segment.contract_type
+                      AND c.status IN ('draft', 'awaiting_payment') AND c.end_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)
+                + COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    JOIN contract c ON c.id = r.contract_id
+                    WHERE c.contract_type = segment.contract_type AND r.is_deleted = FALSE
+                      AND r.recognition_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)
+             FROM (VALUES ('private'), ('corporate')) AS segment (contract_type)",
+        )
+        .bind(filter.product_id)
+        .bind(pesel)
+        .bind(krs)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!(
+                "Failed to calculate revenue by segment: {:?}",
+                e
+            ))
+        })
+    }
+
+    // Returns (obligation kind, recognized, pending) for licenses and support. Recognized is
+    // what the revenue schedule has recognized so far, so support counts month by month.
+    pub async fn calculate_revenue_by_obligation(
+        pool: &Pool<Postgres>,
+        filter: &RevenueFilter,
+    ) -> Result<Vec<(String, BigDecimal, BigDecimal)>, AppError> {
+        let (pesel, krs) = filter.client_keys();
+        sqlx::query_as::<_, (String, BigDecimal, BigDecimal)>(
+            "SELECT obligation.kind,
+                COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    JOIN contract c ON c.id = r.contract_id
+                    WHERE r.kind = obligation.kind AND r.is_deleted = FALSE
+                      AND r.recognition_date <= (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0),
+                COALESCE((
+                    SELECT SUM(o.allocated_price)
+                    FROM performance_obligation o
+                    JOIN contract c ON c.id = o.contract_id
+                    WHERE o.kind = obligation.kind AND o.is_deleted = FALSE
+                      AND c.status IN ('draft', 'awaiting_payment') AND c.end_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)
+                + COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    JOIN contract c ON c.id = r.contract_id
+                    WHERE r.kind = obligation.kind AND r.is_deleted = FALSE
+                      AND r.recognition_date > (NOW() AT TIME ZONE 'UTC')
+                      AND ($1::INTEGER IS NULL OR c.product_id = $1)
+                      AND ($2::TEXT IS NULL OR c.personal_client_pesel = $2)
+                      AND ($3::TEXT IS NULL OR c.company_client_krs = $3)
+                ), 0)
+             FROM (VALUES ('license'), ('support')) AS obligation (kind)",
+        )
+        .bind(filter.product_id)
+        .bind(pesel)
+        .bind(krs)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!(
+                "Failed to calculate revenue by obligation: {:?}",
+                e
+            ))
+        })
+    }
+
+    // Returns (contract_id, cash received, revenue recognized) for every contract that was
+    // signed or active at the end of the `as_of` day, counting only what happened before it
+    pub async fn calculate_deferred_revenue(
+        pool: &Pool<Postgres>,
+        as_of: chrono::NaiveDate,
+    ) -> Result<Vec<(i32, BigDecimal, BigDecimal)>, AppError> {
+        let cutoff = as_of
+            .succ_opt()
+            .and_then(|day| day.and_hms_opt(0, 0, 0))
+            .ok_or_else(|| AppError::BadRequest("Invalid as_of date".to_string()))?;
+
+        sqlx::query_as::<_, (i32, BigDecimal, BigDecimal)>(
+            "SELECT c.id,
+                COALESCE((
+                    SELECT SUM(p.amount)
+                    FROM payment p
+                    WHERE p.contract_id = c.id AND p.is_deleted = FALSE AND p.payment_date < $1
+                ), 0),
+                COALESCE((
+                    SELECT SUM(r.amount)
+                    FROM revenue_schedule r
+                    WHERE r.contract_id = c.id AND r.is_deleted = FALSE AND r.recognition_date < $1
+                ), 0)
+             FROM contract c
+             WHERE (
+                SELECT h.to_status
+                FROM contract_status_history h
+                WHERE h.contract_id = c.id AND h.changed_at < $1
+                ORDER BY h.changed_at DESC, h.id DESC
+                LIMIT 1
+             ) IN ('signed', 'active')
+             ORDER BY c.id",
+        )
+        .bind(cutoff)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to calculate deferred revenue: {:?}", e))
+        })
+    }
+
+    // First day of the period that contains `date`
+    pub fn period_start(date: NaiveDate, granularity: Granularity) -> NaiveDate {
+        let months_into_year = date.month0() - date.month0() % granularity.months();
+        NaiveDate::from_ymd_opt(date.year(), months_into_year + 1, 1)
+            .expect("First day of the period is always valid")
+    }
+
+    // Longest time series a single request can ask for, 20 years of months
+    pub const MAX_TIME_SERIES_BUCKETS: u32 = 240;
+
+    // Number of periods between `from` and `to`, both inclusive
+    pub fn period_count(from: NaiveDate, to: NaiveDate, granularity: Granularity) -> u32 {
+        let (first, last) = (
+            period_start(from, granularity),
+            period_start(to, granularity),
+        );
+        if last < first {
+            return 0;
+        }
+        let months = (last.year() - first.year()) as u32 * 12 + last.month0() - first.month0();
+        months / granularity.months() + 1
+    }
+
+    // Start dates of every period between `from` and `to`, both inclusive
+    pub fn period_starts(
+        from: NaiveDate,
+        to: NaiveDate,
+        granularity: Granularity,
+    ) -> Vec<NaiveDate> {
+        let mut starts = Vec::new();
+        let mut current = period_start(from, granularity);
+        while current <= to {
+            starts.push(current);
+            current = current + Months::new(granularity.months());
+        }
+        starts
+    }
+
+    // Returns (period start, recognized, cash collected) for the periods that had any activity
+    pub async fn calculate_revenue_time_series(
+        pool: &Pool<Postgres>,
+        from: NaiveDate,
+        to: NaiveDate,
+        granularity: Granularity,
+    ) -> Result<Vec<(NaiveDate, BigDecimal, BigDecimal)>, AppError> {
+        let range_start = from.and_hms_opt(0, 0, 0).expect("Midnight is always valid");
+        let range_end = to
+            .succ_opt()
+            .and_then(|day| day.and_hms_opt(0, 0, 0))
+            .ok_or_else(|| AppError::BadRequest("Invalid to date".to_string()))?;
+        let unit = match granularity {
+            Granularity::Month => "month",
+            Granularity::Quarter => "quarter",
+            Granularity::Year => "year",
+        };
+
+        sqlx::query_as::<_, (NaiveDate, BigDecimal, BigDecimal)>(
+            "SELECT period::DATE, SUM(recognized), SUM(cash_collected)
+             FROM (
+                SELECT date_trunc($1, r.recognition_date) AS period, r.amount AS recognized, 0 AS cash_collected
+                FROM revenue_schedule r
+                WHERE r.is_deleted = FALSE
+                  AND r.recognition_date >= $2 AND r.recognition_date < $3
+                UNION ALL
+                SELECT date_trunc($1, p.payment_date) AS period, 0 AS recognized, p.amount AS cash_collected
+                FROM payment p
+                WHERE p.contract_id IS NOT NULL AND p.is_deleted = FALSE
+                  AND p.payment_date >= $2 AND p.payment_date < $3
+             ) activity
+             GROUP BY period
+             ORDER BY period",
+        )
+        .bind(unit)
+        .bind(range_start)
+        .bind(range_end)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!(
+                "Failed to calculate revenue time series: {:?}",
+                e
+            ))
+        })
+    }
+}
+
+pub mod obligations {
+    use super::*;
+    use crate::client::{PerformanceObligation, RecognitionKind, RecognitionRule};
+    use bigdecimal::{RoundingMode, Zero};
+
+    // every year of support is worth 1 000 zł on its own
+    pub const SUPPORT_PRICE_PER_YEAR: i32 = 1000;
+
+    // Splits the contract price into a perpetual license and one support obligation per
+    // supported year, in proportion to their list prices (relative standalone selling prices)
+    pub fn allocate_obligations(
+        contract_price: &BigDecimal,
+        license_list_price: &BigDecimal,
+        years_supported: i32,
+    ) -> Vec<PerformanceObligation> {
+        let years_supported = years_supported.max(0);
+        let support_list_price = BigDecimal::from(years_supported * SUPPORT_PRICE_PER_YEAR);
+        let total_list_price = license_list_price + &support_list_price;
+
+        let license_price = if total_list_price.is_zero() || years_supported == 0 {
+            contract_price.clone()
+        } else {
+            (contract_price * license_list_price / total_list_price)
+                .with_scale_round(2, RoundingMode::HalfUp)
+        };
+
+        let mut obligations = vec![PerformanceObligation {
+            kind: RecognitionKind::License,
+            support_year: None,
+            allocated_price: license_price.clone(),
+            recognition_rule: RecognitionRule::PointInTime,
+        }];
+
+        if years_supported == 0 {
+            return obligations;
+        }
+
+        // the last year takes whatever is left after rounding
+        let support_price = contract_price - &license_price;
+        let yearly_price = (&support_price / BigDecimal::from(years_supported))
+            .with_scale_round(2, RoundingMode::Down);
+        let mut remaining = support_price;
+        for year in 1..=years_supported {
+            let allocated_price = if year == years_supported {
+                remaining.clone()
+            } else {
+                yearly_price.clone()
+            };
+            remaining -= &allocated_price;
+            obligations.push(PerformanceObligation {
+                kind: RecognitionKind::Support,
+                support_year: Some(year),
+                allocated_price,
+                recognition_rule: RecognitionRule::Ratably,
+            });
+        }
+
+        obligations
+    }
+
+    pub async fn create_obligations_for_contract(
+        conn: &mut PgConnection,
+        contract_id: i32,
+    ) -> Result<(), AppError> {
+        let (contract_price, years_supported, license_list_price) =
+            sqlx::query_as::<_, (BigDecimal, i32, BigDecimal)>(
+                "SELECT c.price, c.years_supported, s.price
+                 FROM contract c
+                 JOIN software s ON s.id = c.product_id
+                 WHERE c.id = $1",
+            )
+            .bind(contract_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| {
+                AppError::InternalServerError(format!(
+                    "Failed to get contract for performance obligations: {:?}",
+                    e
+                ))
+            })?;
+
+        let obligations =
+            allocate_obligations(&contract_price, &license_list_price, years_supported);
+
+        for obligation in obligations {
+            sqlx::query(
+                "INSERT INTO performance_obligation (contract_id, kind, support_year, allocated_price, recognition_rule) VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(contract_id)
+            .bind(obligation.kind.as_str())
+            .bind(obligation.support_year)
+            .bind(obligation.allocated_price)
+            .bind(obligation.recognition_rule.as_str())
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| {
+                AppError::InternalServerError(format!(
+                    "Failed to create performance obligation: {:?}",
+                    e
+                ))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_obligations_for_contract(
+        conn: &mut PgConnection,
+        contract_id: i32,
+    ) -> Result<Vec<PerformanceObligation>, AppError> {
+        let rows = sqlx::query_as::<_, (String, Option<i32>, BigDecimal, String)>(
+            "SELECT kind, support_year, allocated_price, recognition_rule
+             FROM performance_obligation
+             WHERE contract_id = $1 AND is_deleted = FALSE
+             ORDER BY support_year NULLS FIRST, id",
+        )
+        .bind(contract_id)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to get performance obligations: {:?}", e))
+        })?;
+
+        rows.into_iter()
+            .map(|(kind, support_year, allocated_price, recognition_rule)| {
+                Ok(PerformanceObligation {
+                    kind: RecognitionKind::from_db(&kind).ok_or_else(|| {
+                        AppError::InternalServerError(format!("Unknown obligation kind: {}", kind))
+                    })?,
+                    support_year,
+                    allocated_price,
+                    recognition_rule: RecognitionRule::from_db(&recognition_rule).ok_or_else(
+                        || {
+                            AppError::InternalServerError(format!(
+                                "Unknown recognition rule: {}",
+                                recognition_rule
+                            ))
+                        },
+                    )?,
+                })
+            })
+            .collect()
+    }
+}
+
+pub mod revenue_schedule {
+    use super::*;
+    use crate::client::{
+        PerformanceObligation, RecognitionKind, RecognitionRule, RevenueScheduleEntry,
+    };
+    use bigdecimal::RoundingMode;
+    use chrono::Months;
+
+    // Point in time obligations are recognized when the contract is paid, ratable support
+    // years are recognized evenly at the end of every month of their year of service
+    pub fn build_revenue_schedule(
+        obligations: &[PerformanceObligation],
+        paid_at: DateTime<Utc>,
+    ) -> Vec<RevenueScheduleEntry> {
+        let mut entries = Vec::new();
+
+        for obligation in obligations {
+            match obligation.recognition_rule {
+                RecognitionRule::PointInTime => entries.push(RevenueScheduleEntry {
+                    kind: obligation.kind,
+                    amount: obligation.allocated_price.clone(),
+                    recognition_date: paid_at,
+                }),
+                RecognitionRule::Ratably => {
+                    let first_month = match (obligation.kind, obligation.support_year) {
+                        (RecognitionKind::Support, Some(year)) => ((year.max(1) - 1) * 12) as u32,
+                        _ => 0,
+                    };
+
+                    // the last month takes whatever is left after rounding
+                    let monthly_amount = (&obligation.allocated_price / BigDecimal::from(12))
+                        .with_scale_round(2, RoundingMode::Down);
+                    let mut remaining = obligation.allocated_price.clone();
+                    for month in 1..=12 {
+                        let amount = if month == 12 {
+                            remaining.clone()
+                        } else {
+                            monthly_amount.clone()
+                        };
+                        remaining -= &amount;
+                        entries.push(RevenueScheduleEntry {
+                            kind: obligation.kind,
+                            amount,
+                            recognition_date: paid_at
+                                .checked_add_months(Months::new(first_month + month))
+                                .expect("Support period is out of range"),
+                        });
+                    }
+                }
+            }
+        }
+
+        entries.sort_by_key(|entry| entry.recognition_date);
+        entries
+    }
+
+    // A contract paid again after a refund stopped its schedule picks it up where it left off,
+    // entries that fell due in the meantime are recognized at `paid_at`
+    pub async fn create_schedule_for_contract(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        paid_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let schedule_exists = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM revenue_schedule WHERE contract_id = $1)",
+        )
+        .bind(contract_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to check revenue schedule: {:?}", e))
+        })?;
+        if schedule_exists {
+            sqlx::query(
+                "UPDATE revenue_schedule
+                 SET is_deleted = FALSE, recognition_date = GREATEST(recognition_date, $2)
+                 WHERE contract_id = $1 AND is_deleted = TRUE",
+            )
+            .bind(contract_id)
+            .bind(paid_at.naive_utc())
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| {
+                AppError::InternalServerError(format!("Failed to resume revenue schedule: {:?}", e))
+            })?;
+            return Ok(());
+        }
+
+        let obligations = obligations::get_obligations_for_contract(conn, contract_id).await?;
+        let entries = build_revenue_schedule(&obligations, paid_at);
+
+        for entry in entries {
+            sqlx::query(
+                "INSERT INTO revenue_schedule (contract_id, kind, amount, recognition_date) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(contract_id)
+            .bind(entry.kind.as_str())
+            .bind(entry.amount)
+            .bind(entry.recognition_date.naive_utc())
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| {
+                AppError::InternalServerError(format!(
+                    "Failed to create revenue schedule: {:?}",
+                    e
+                ))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Stops recognizing revenue for a contract that is no longer paid. Only entries after `at`
+    // are dropped, what was recognized before stays in its period.
+    pub async fn stop_schedule_for_contract(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE revenue_schedule SET is_deleted = TRUE
+             WHERE contract_id = $1 AND is_deleted = FALSE AND recognition_date > $2",
+        )
+        .bind(contract_id)
+        .bind(at.naive_utc())
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to remove revenue schedule: {:?}", e))
+        })?;
+        Ok(())
+    }
+
+    // A contract that gave back everything the client paid keeps no revenue. What was
+    // recognized is reversed with entries dated `at`, so closed periods keep what they reported.
+    pub async fn reverse_recognized_revenue(
+        conn: &mut PgConnection,
+        contract_id: i32,
+        at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO revenue_schedule (contract_id, kind, amount, recognition_date)
+             SELECT contract_id, kind, -SUM(amount), $2
+             FROM revenue_schedule
+             WHERE contract_id = $1 AND is_deleted = FALSE AND recognition_date <= $2
+             GROUP BY contract_id, kind
+             HAVING SUM(amount) <> 0",
+        )
+        .bind(contract_id)
+        .bind(at.naive_utc())
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to reverse recognized revenue: {:?}", e))
+        })?;
+        Ok(())
+    }
+
+    pub async fn get_schedule_for_contract(
+        pool: &Pool<Postgres>,
+        contract_id: i32,
+    ) -> Result<Vec<RevenueScheduleEntry>, AppError> {
+        let rows = sqlx::query_as::<_, (String, BigDecimal, chrono::NaiveDateTime)>(
+            "SELECT kind, amount, recognition_date
+             FROM revenue_schedule
+             WHERE contract_id = $1 AND is_deleted = FALSE
+             ORDER BY recognition_date, id",
+        )
+        .bind(contract_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to get revenue schedule: {:?}", e))
+        })?;
+
+        rows.into_iter()
+            .map(|(kind, amount, recognition_date)| {
+                let kind = RecognitionKind::from_db(&kind).ok_or_else(|| {
+                    AppError::InternalServerError(format!("Unknown recognition kind: {}", kind))
+                })?;
+                Ok(RevenueScheduleEntry {
+                    kind,
+                    amount,
+                    recognition_date: DateTime::from_naive_utc_and_offset(recognition_date, Utc),
+                })
+            })
+            .collect()
+    }
+}
+
+pub mod periods {
+    use super::*;
+    use chrono::{Datelike, NaiveDate};
+
+    // Parses a "yyyy-mm" period into the first day of that month
+    pub fn parse_period(period: &str) -> Option<NaiveDate> {
+        let (year, month) = period.split_once('-')?;
+        let is_number =
+            |part: &str, len: usize| part.len() == len && part.chars().all(|c| c.is_ascii_digit());
+        if !is_number(year, 4) || !is_number(month, 2) {
+            return None;
+        }
+        NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, 1)
+    }
+
+    pub fn period_of(date: &DateTime<Utc>) -> NaiveDate {
+        NaiveDate::from_ymd_opt(date.year(), date.month(), 1)
+            .expect("First day of the month is always valid")
+    }
+
+    pub async fn is_period_closed(
+        conn: &mut PgConnection,
+        period_start: NaiveDate,
+    ) -> Result<bool, AppError> {
+        sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM accounting_period WHERE period_start = $1)",
+        )
+        .bind(period_start)
+        .fetch_one(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to check accounting period: {:?}", e))
+        })
+    }
+
+    // Rejects writing payments or contracts dated in a closed accounting period, `date` is
+    // the date of the record being written. Runs in the caller's transaction.
+    pub async fn check_period_is_open(
+        conn: &mut PgConnection,
+        date: &DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let period_start = period_of(date);
+        if is_period_closed(conn, period_start).await? {
+            return Err(AppError::BadRequest(format!(
+                "Accounting period {} is closed, corrections have to be made in the current open period",
+                period_start.format("%Y-%m")
+            )));
+        }
+        Ok(())
+    }
+
+    // Returns false when the period was already closed, e.g. by a concurrent request
+    pub async fn close_period(
+        pool: &Pool<Postgres>,
+        period_start: NaiveDate,
+    ) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "INSERT INTO accounting_period (period_start) VALUES ($1) ON CONFLICT DO NOTHING",
+        )
+        .bind(period_start)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to close accounting period: {:?}", e))
+        })?;
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+pub mod subscriptions {
+    use super::*;
+    use crate::client::{Subscription, SubscriptionPaymentKind, SubscriptionStatus};
+    use bigdecimal::RoundingMode;
+    use chrono::{Months, NaiveDateTime};
+
+    pub const MIN_RENEWAL_PERIOD_MONTHS: i32 = 1;
+    pub const MAX_RENEWAL_PERIOD_MONTHS: i32 = 24;
+
+    pub fn validate_renewal_period(months: i32) -> Result<(), String> {
+        if !(MIN_RENEWAL_PERIOD_MONTHS..=MAX_RENEWAL_PERIOD_MONTHS).contains(&months) {
+            return Err(format!(
+                "Renewal period must be between {} and {} months",
+                MIN_RENEWAL_PERIOD_MONTHS, MAX_RENEWAL_PERIOD_MONTHS
+            ));
+        }
+        Ok(())
+    }
+
+    #[derive(sqlx::FromRow)]
+    struct SubscriptionRow {
+        id: i32,
+        name: String,
+        personal_client_pesel: Option<String>,
+        company_client_krs: Option<String>,
+        product_id: i32,
+        renewal_period_months: i32,
+        price: BigDecimal,
+        start_date: NaiveDateTime,
+        billing_anchor: NaiveDateTime,
+        period_offset: i32,
+        status: String,
+        cancelled_at: Option<NaiveDateTime>,
+    }
+
+    const SUBSCRIPTION_COLUMNS: &str = "id, name, personal_client_pesel, company_client_krs, product_id, renewal_period_months, price, start_date, billing_anchor, period_offset, status, cancelled_at";
+
+    fn subscription_from_row(row: SubscriptionRow) -> Result<Subscription, AppError> {
+        let SubscriptionRow {
+            id,
+            name,
+            personal_client_pesel: pesel,
+            company_client_krs: krs,
+            product_id,
+            renewal_period_months,
+            price,
+            start_date,
+            billing_anchor,
+            period_offset,
+            status,
+            cancelled_at,
+        } = row;
+
+        let client_id = match (pesel, krs) {
+            (Some(pesel), None) => ClientId::Individual(pesel),
+            (None, Some(krs)) => ClientId::Company(krs),
+            _ => {
+                return Err(AppError::InternalServerError(format!(
+                    "Subscription {} has no client",
+                    id
+                )))
+            }
+        };
+
+        Ok(Subscription {
+            id,
+            name,
+            product_id,
+            client_id,
+            renewal_period_months,
+            price,
+            start_date: DateTime::from_naive_utc_and_offset(start_date, Utc),
+            billing_anchor: DateTime::from_naive_utc_and_offset(billing_anchor, Utc),
+            period_offset,
+            status: SubscriptionStatus::from_db(&status).ok_or_else(|| {
+                AppError::InternalServerError(format!("Unknown subscription status: {}", status))
+            })?,
+            cancelled_at: cancelled_at.map(|date| DateTime::from_naive_utc_and_offset(date, Utc)),
+        })
+    }
+
+    pub async fn check_if_client_has_active_subscription(
+        pool: &Pool<Postgres>,
+        client_id: &ClientId,
+        product_id: i32,
+    ) -> Result<bool, AppError> {
+        let (pesel, krs) = match client_id {
+            ClientId::Individual(pesel) => (Some(pesel), None),
+            ClientId::Company(krs) => (None, Some(krs)),
+        };
+        sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(
+                SELECT 1 FROM subscription
+                WHERE product_id = $1 AND status = 'active' AND is_deleted = FALSE
+                  AND (personal_client_pesel = $2 OR company_client_krs = $3)
+            )",
+        )
+        .bind(product_id)
+        .bind(pesel)
+        .bind(krs)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to check subscriptions: {:?}", e))
+        })
+    }
+
+    pub async fn create_subscription(
+        pool: &Pool<Postgres>,
+        name: &str,
+        client_id: &ClientId,
+        product_id: i32,
+        renewal_period_months: i32,
+        price: &BigDecimal,
+    ) -> Result<Subscription, AppError> {
+        let (contract_type, pesel, krs) = match client_id {
+            ClientId::Individual(pesel) => ("private", Some(pesel), None),
+            ClientId::Company(krs) => ("corporate", None, Some(krs)),
+        };
+
+        let row = sqlx::query_as::<_, SubscriptionRow>(&format!(
+            "INSERT INTO subscription (name, contract_type, personal_client_pesel, company_client_krs, product_id, renewal_period_months, price)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             RETURNING {}",
+            SUBSCRIPTION_COLUMNS
+        ))
+        .bind(name)
+        .bind(contract_type)
+        .bind(pesel)
+        .bind(krs)
+        .bind(product_id)
+        .bind(renewal_period_months)
+        .bind(price)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to create subscription: {:?}", e))
+        })?;
+
+        subscription_from_row(row)
+    }
+
+    // Only returns the subscription if it belongs to the given client. It stays locked
+    // until the transaction ends so its plan and payments can't change in the meantime.
+    pub async fn lock_subscription(
+        conn: &mut PgConnection,
+        client_id: &ClientId,
+        subscription_id: i32,
+    ) -> Result<Option<Subscription>, AppError> {
+        let (pesel, krs) = match client_id {
+            ClientId::Individual(pesel) => (Some(pesel), None),
+            ClientId::Company(krs) => (None, Some(krs)),
+        };
+        let row = sqlx::query_as::<_, SubscriptionRow>(&format!(
+            "SELECT {} FROM subscription
+             WHERE id = $1 AND is_deleted = FALSE
+               AND (personal_client_pesel = $2 OR company_client_krs = $3)
+             FOR UPDATE",
+            SUBSCRIPTION_COLUMNS
+        ))
+        .bind(subscription_id)
+        .bind(pesel)
+        .bind(krs)
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to lock subscription: {:?}", e))
+        })?;
+
+        row.map(subscription_from_row).transpose()
+    }
+
+    // Index of the renewal period `date` falls into, the first period is 0
+    pub fn period_number_at(
+        start_date: DateTime<Utc>,
+        renewal_period_months: i32,
+        date: DateTime<Utc>,
+    ) -> i32 {
+        let mut period_number = 0;
+        while let Some(period_end) =
+            period_bounds(start_date, renewal_period_months, period_number).map(|(_, end)| end)
+        {
+            if period_end > date {
+                break;
+            }
+            period_number += 1;
+        }
+        period_number
+    }
+
+    // Start and end of the given renewal period
+    pub fn period_bounds(
+        start_date: DateTime<Utc>,
+        renewal_period_months: i32,
+        period_number: i32,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let months = renewal_period_months.max(1) as u32;
+        let period_start =
+            start_date.checked_add_months(Months::new(months * period_number.max(0) as u32))?;
+        let period_end = period_start.checked_add_months(Months::new(months))?;
+        Some((period_start, period_end))
+    }
+
+    // Number of the renewal period the subscription is in at `date`. Before the billing
+    // anchor the subscription is still in the period during which its plan was changed.
+    pub fn current_period_number(subscription: &Subscription, date: DateTime<Utc>) -> i32 {
+        if date < subscription.billing_anchor {
+            return subscription.period_offset - 1;
+        }
+        subscription.period_offset
+            + period_number_at(
+                subscription.billing_anchor,
+                subscription.renewal_period_months,
+                date,
+            )
+    }
+
+    // A renewal period that ended without being paid ends the subscription
+    pub fn has_lapsed(
+        subscription: &Subscription,
+        paid_periods: &[i32],
+        date: DateTime<Utc>,
+    ) -> bool {
+        let period_number = current_period_number(subscription, date);
+        period_number > 0 && !paid_periods.contains(&(period_number - 1))
+    }
+
+    // Part of `amount` that corresponds to `remaining_seconds` out of `period_seconds`
+    pub fn prorate(amount: &BigDecimal, remaining_seconds: i64, period_seconds: i64) -> BigDecimal {
+        if period_seconds <= 0 {
+            return BigDecimal::from(0);
+        }
+        let remaining_seconds = remaining_seconds.clamp(0, period_seconds);
+        (amount * BigDecimal::from(remaining_seconds) / BigDecimal::from(period_seconds))
+            .with_scale_round(2, RoundingMode::HalfUp)
+    }
+
+    // Discounts only apply to the first renewal period
+    pub fn amount_due_for_period(
+        price: &BigDecimal,
+        discount: &BigDecimal,
+        period_number: i32,
+    ) -> BigDecimal {
+        if period_number == 0 {
+            (price * (BigDecimal::from(1) - discount)).with_scale_round(2, RoundingMode::HalfUp)
+        } else {
+            price.with_scale_round(2, RoundingMode::HalfUp)
+        }
+    }
+
+    pub async fn get_paid_periods(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+    ) -> Result<Vec<i32>, AppError> {
+        sqlx::query_scalar::<_, i32>(
+            "SELECT period_number FROM payment
+             WHERE subscription_id = $1 AND subscription_payment_kind = 'renewal' AND is_deleted = FALSE
+             ORDER BY period_number",
+        )
+        .bind(subscription_id)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to get subscription payments: {:?}", e))
+        })
+    }
+
+    pub async fn get_paid_amount_for_period(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+        period_number: i32,
+    ) -> Result<BigDecimal, AppError> {
+        sqlx::query_scalar::<_, BigDecimal>(
+            "SELECT COALESCE(SUM(amount), 0) FROM payment
+             WHERE subscription_id = $1 AND period_number = $2 AND is_deleted = FALSE",
+        )
+        .bind(subscription_id)
+        .bind(period_number)
+        .fetch_one(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to get subscription payments: {:?}", e))
+        })
+    }
+
+    // Books the part of a plan change the client still owes, it becomes due with the
+    // next renewal payment
+    pub async fn add_charge(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+        period_number: i32,
+        amount: &BigDecimal,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO subscription_charge (subscription_id, period_number, amount) VALUES ($1, $2, $3)",
+        )
+        .bind(subscription_id)
+        .bind(period_number)
+        .bind(amount)
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to add subscription charge: {:?}", e))
+        })?;
+        Ok(())
+    }
+
+    // Returns (charge_id, period_number, amount) of every charge that hasn't been paid yet
+    pub async fn get_outstanding_charges(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+    ) -> Result<Vec<(i32, i32, BigDecimal)>, AppError> {
+        sqlx::query_as::<_, (i32, i32, BigDecimal)>(
+            "SELECT id, period_number, amount FROM subscription_charge
+             WHERE subscription_id = $1 AND payment_id IS NULL
+             ORDER BY id",
+        )
+        .bind(subscription_id)
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to get subscription charges: {:?}", e))
+        })
+    }
+
+    // Pays the outstanding charges, each one is booked in the period it was charged for
+    pub async fn settle_charges(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+        charges: Vec<(i32, i32, BigDecimal)>,
+    ) -> Result<(), AppError> {
+        for (charge_id, period_number, amount) in charges {
+            let payment_id = payments::create_subscription_payment_record_in_db(
+                conn,
+                subscription_id,
+                period_number,
+                SubscriptionPaymentKind::ProrationCharge,
+                amount,
+            )
+            .await?;
+            sqlx::query("UPDATE subscription_charge SET payment_id = $1 WHERE id = $2")
+                .bind(payment_id)
+                .bind(charge_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| {
+                    AppError::InternalServerError(format!(
+                        "Failed to settle subscription charge: {:?}",
+                        e
+                    ))
+                })?;
+        }
+        Ok(())
+    }
+
+    // Switches the subscription to the new plan, the new renewal periods start at `billing_anchor`
+    pub async fn change_plan(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+        product_id: i32,
+        renewal_period_months: i32,
+        price: &BigDecimal,
+        billing_anchor: DateTime<Utc>,
+        period_offset: i32,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE subscription
+             SET product_id = $1, renewal_period_months = $2, price = $3, billing_anchor = $4, period_offset = $5
+             WHERE id = $6",
+        )
+        .bind(product_id)
+        .bind(renewal_period_months)
+        .bind(price)
+        .bind(billing_anchor.naive_utc())
+        .bind(period_offset)
+        .bind(subscription_id)
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to change subscription plan: {:?}", e))
+        })?;
+        Ok(())
+    }
+
+    pub async fn find_active_subscriptions(pool: &Pool<Postgres>) -> Result<Vec<i32>, AppError> {
+        sqlx::query_scalar::<_, i32>(
+            "SELECT id FROM subscription WHERE status = 'active' AND is_deleted = FALSE ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to find active subscriptions: {:?}", e))
+        })
+    }
+
+    // Cancels the subscription if a renewal period ended without being paid, returns
+    // whether it was cancelled
+    pub async fn cancel_if_lapsed(
+        pool: &Pool<Postgres>,
+        subscription_id: i32,
+        date: DateTime<Utc>,
+    ) -> Result<bool, AppError> {
+        let db_error = |e: sqlx::Error| {
+            AppError::InternalServerError(format!("Failed to cancel lapsed subscription: {:?}", e))
+        };
+        let mut tx = pool.begin().await.map_err(db_error)?;
+
+        let row = sqlx::query_as::<_, SubscriptionRow>(&format!(
+            "SELECT {} FROM subscription WHERE id = $1 AND is_deleted = FALSE FOR UPDATE",
+            SUBSCRIPTION_COLUMNS
+        ))
+        .bind(subscription_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?;
+        let Some(subscription) = row.map(subscription_from_row).transpose()? else {
+            return Ok(false);
+        };
+        if subscription.status != SubscriptionStatus::Active {
+            return Ok(false);
+        }
+
+        let paid_periods = get_paid_periods(&mut tx, subscription_id).await?;
+        if !has_lapsed(&subscription, &paid_periods, date) {
+            return Ok(false);
+        }
+
+        cancel_subscription(&mut tx, subscription_id).await?;
+        tx.commit().await.map_err(db_error)?;
+        Ok(true)
+    }
+
+    pub async fn cancel_subscription(
+        conn: &mut PgConnection,
+        subscription_id: i32,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE subscription SET status = $1, cancelled_at = CURRENT_TIMESTAMP WHERE id = $2",
+        )
+        .bind(SubscriptionStatus::Cancelled.as_str())
+        .bind(subscription_id)
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to cancel subscription: {:?}", e))
+        })?;
+        Ok(())
     }
 }
@@ -0,0 +1,85 @@
+use bigdecimal::{BigDecimal, RoundingMode};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// All amounts are stored in PLN, rates say how much of the target currency 1 PLN buys
+pub const BASE_CURRENCY: &str = "PLN";
+
+pub trait ExchangeRateProvider: Send + Sync {
+    fn rate_from_pln(&self, currency: &str) -> Option<BigDecimal>;
+}
+
+pub type SharedRateProvider = Arc<dyn ExchangeRateProvider>;
+
+#[derive(serde::Deserialize)]
+struct RatesFile {
+    base: String,
+    rates: HashMap<String, String>,
+}
+
+// Reads the rates from a local JSON file, e.g.
+// { "base": "PLN", "rates": { "EUR": "0.2340", "USD": "0.2530" } }
+pub struct JsonFileRateProvider {
+    rates: HashMap<String, BigDecimal>,
+}
+
+impl JsonFileRateProvider {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read exchange rates from {}: {}", path, e))?;
+        Self::from_json(&contents)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, String> {
+        let file: RatesFile = serde_json::from_str(contents)
+            .map_err(|e| format!("Failed to parse exchange rates: {}", e))?;
+
+        if !file.base.eq_ignore_ascii_case(BASE_CURRENCY) {
+            return Err(format!(
+                "Exchange rates must be based on {}, got {}",
+                BASE_CURRENCY, file.base
+            ));
+        }
+
+        let mut rates = HashMap::new();
+        for (currency, rate) in file.rates {
+            let rate = BigDecimal::from_str(&rate)
+                .map_err(|e| format!("Invalid exchange rate for {}: {}", currency, e))?;
+            if rate <= BigDecimal::from(0) {
+                return Err(format!("Exchange rate for {} must be positive", currency));
+            }
+            rates.insert(currency.to_uppercase(), rate);
+        }
+
+        Ok(Self { rates })
+    }
+}
+
+impl ExchangeRateProvider for JsonFileRateProvider {
+    fn rate_from_pln(&self, currency: &str) -> Option<BigDecimal> {
+        let currency = currency.to_uppercase();
+        if currency == BASE_CURRENCY {
+            return Some(BigDecimal::from(1));
+        }
+        self.rates.get(&currency).cloned()
+    }
+}
+
+// Converted amounts are rounded to the grosz/cent, same as NUMERIC(10, 2) in the database
+pub fn convert(amount: &BigDecimal, rate: &BigDecimal) -> BigDecimal {
+    (amount * rate).with_scale_round(2, RoundingMode::HalfUp)
+}
+
+// Converts recognized and pending revenue and adds them up after rounding, so the
+// converted total always matches its parts
+pub fn convert_with_total(
+    recognized: &BigDecimal,
+    pending: &BigDecimal,
+    rate: &BigDecimal,
+) -> (BigDecimal, BigDecimal, BigDecimal) {
+    let recognized = convert(recognized, rate);
+    let pending = convert(pending, rate);
+    let total = &recognized + &pending;
+    (recognized, pending, total)
+}
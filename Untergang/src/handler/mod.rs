@@ -1,32 +1,63 @@
-use crate::db::payments;
+use crate::db::{
+    contracts, installments, payments, periods, pricing, revenue, revenue_schedule, subscriptions,
+};
 use axum::{
-    extract::{Json, State},
+    extract::{Json, Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
+    Extension,
 };
-use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
-use chrono::{DateTime, Utc};
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, NaiveDate, Utc};
 use sqlx::{Pool, Postgres};
 
 use crate::{
-    client::{Client, ClientId},
+    client::{
+        Client, ClientId, ClientKind, Contract, ContractDetails, ContractFilter, ContractPage,
+        ContractStatus, DeferredRevenueEntry, DeferredRevenueReport, Granularity,
+        InstallmentPlanEntry, ObligationRevenue, OverdueInstallment, PaymentRefund,
+        PredictedObligationRevenue, PredictedProductRevenue, PredictedRevenueReport,
+        PredictedSegmentRevenue, PriceBreakdown, ProductRevenue, RecognitionKind, RefundSummary,
+        RevenueBucket, RevenueFilter, RevenueReport, RevenueSchedule, RevenueTimeSeries,
+        SegmentRevenue, Subscription, SubscriptionPaymentKind, SubscriptionPlanChange,
+        SubscriptionStatus,
+    },
     db::{
-        check_if_client_exists, check_if_client_has_contract_for_product,
-        check_product_and_client_exist, create_contract_in_db, find_discounts_for_client,
-        get_contract_by_id, get_price_for_product, pay_for_contract,
+        check_if_client_exists, check_if_client_has_contract_for_product, check_if_contract_exists,
+        check_if_product_exists, check_product_and_client_exist, create_contract_in_db,
+        find_discounts_for_client, get_contract_by_id, get_payments_for_contract,
+        get_price_for_product,
     },
+    exchange::{self, SharedRateProvider, BASE_CURRENCY},
 };
 
 #[derive(Debug)]
 pub enum AppError {
     BadRequest(String),
+    // domain rule violations, returned as a JSON body so clients can tell them apart
+    Validation(ValidationError),
     InternalServerError(String),
 }
 
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct ValidationError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl From<ValidationError> for AppError {
+    fn from(error: ValidationError) -> Self {
+        AppError::Validation(error)
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, error_message) = match self {
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            AppError::Validation(error) => {
+                return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+            }
             AppError::InternalServerError(msg) => {
                 eprintln!("Internal Server Error: {}", msg);
                 (
@@ -152,35 +183,20 @@ pub struct PurchaseRequest {
     start_date: DateTime<Utc>,
     end_date: DateTime<Utc>,
     product_id: i32,
-    // price is calculated on the backen
-    // update information is availbable in the database
-    years_supported: i32, // every year costs 1 000 additional zł, can be extended by 1, 2, 3 years
+    // price is calculated on the backend, see db::pricing
+    years_supported: i32, // every year costs 1 000 additional zł, 1 to 3 years unless the product says otherwise
+    // pay in these installments instead of any amounts at any time
+    installments: Option<Vec<InstallmentPlanEntry>>,
 }
 
-pub async fn create_contract(
-    State(pool): State<Pool<Postgres>>,
-    Json(purchase_request): Json<PurchaseRequest>,
-) -> Result<(StatusCode, String), AppError> {
-    // check if the client hasn't already ordered the product
-    let client_has_contract = check_if_client_has_contract_for_product(
-        &pool,
-        purchase_request.client_id.clone(),
-        purchase_request.product_id,
-    )
-    .await
-    .map_err(|e| {
-        AppError::InternalServerError(format!("Failed to check if client has contract: {}", e))
-    })?;
-
-    if client_has_contract {
-        return Err(AppError::BadRequest(
-            "Client already has contract for this product".to_string(),
-        ));
-    }
-
+// Validates the request and prices it the way a new contract would be priced
+async fn price_purchase_request(
+    pool: &Pool<Postgres>,
+    purchase_request: &PurchaseRequest,
+) -> Result<PriceBreakdown, AppError> {
     // check if product and client exist
     let (product_exists, client_exists) = check_product_and_client_exist(
-        &pool,
+        pool,
         purchase_request.product_id,
         purchase_request.client_id.clone(),
     )
@@ -199,34 +215,75 @@ pub async fn create_contract(
         return Err(AppError::BadRequest("Client does not exist".to_string()));
     }
 
-    // get discount for client
-    let discount = find_discounts_for_client(
-        &pool,
+    contracts::validate_signing_window(&purchase_request.start_date, &purchase_request.end_date)?;
+
+    pricing::price_contract(
+        pool,
         purchase_request.product_id,
-        purchase_request.client_id.clone(),
+        purchase_request.years_supported,
+        &purchase_request.client_id,
     )
     .await
-    .map_err(|e| AppError::InternalServerError(format!("Failed to get discount: {}", e)))?
-    .unwrap_or(BigDecimal::from_f64(0.0).expect("Failed to convert 0.0 to BigDecimal"));
+}
 
-    let price = get_price_for_product(&pool, purchase_request.product_id)
-        .await
-        .map_err(|e| AppError::InternalServerError(format!("Failed to get price: {:?}", e)))?;
+// Quotes the price of a contract without creating it
+pub async fn quote_contract(
+    State(pool): State<Pool<Postgres>>,
+    Json(purchase_request): Json<PurchaseRequest>,
+) -> Result<Json<PriceBreakdown>, AppError> {
+    Ok(Json(
+        price_purchase_request(&pool, &purchase_request).await?,
+    ))
+}
 
-    let final_price = price
-        * (BigDecimal::from_f64(1.0).expect("Failed to convert 1.0 to BigDecimal") - discount);
+pub async fn create_contract(
+    State(pool): State<Pool<Postgres>>,
+    Json(purchase_request): Json<PurchaseRequest>,
+) -> Result<(StatusCode, String), AppError> {
+    let price = price_purchase_request(&pool, &purchase_request).await?;
+    if let Some(plan) = &purchase_request.installments {
+        installments::validate_plan(
+            plan,
+            &price.final_price,
+            &purchase_request.start_date,
+            &purchase_request.end_date,
+        )?;
+    }
 
-    create_contract_in_db(
-        &pool,
-        &final_price,
+    let db_error =
+        |e: sqlx::Error| AppError::InternalServerError(format!("Failed to create contract: {}", e));
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    // check if the client hasn't already ordered the product
+    let client_has_contract = check_if_client_has_contract_for_product(
+        &mut tx,
+        purchase_request.client_id.clone(),
+        purchase_request.product_id,
+    )
+    .await
+    .map_err(|e| {
+        AppError::InternalServerError(format!("Failed to check if client has contract: {}", e))
+    })?;
+    if client_has_contract {
+        return Err(AppError::BadRequest(
+            "Client already has contract for this product".to_string(),
+        ));
+    }
+
+    let contract_id = create_contract_in_db(
+        &mut tx,
+        &price,
         &purchase_request.product_id,
         &purchase_request.client_id,
         &purchase_request.start_date,
         &purchase_request.end_date,
-        &purchase_request.years_supported,
+        None,
     )
-    .await
-    .map_err(|e| AppError::InternalServerError(format!("Failed to create contract: {}", e)))?;
+    .await?;
+    if let Some(plan) = &purchase_request.installments {
+        installments::create_plan(&mut tx, contract_id, plan).await?;
+    }
+    tx.commit().await.map_err(db_error)?;
 
     Ok((StatusCode::CREATED, "Contract created".to_string()))
 }
@@ -284,114 +341,1087 @@ pub async fn create_payment(
             _ => AppError::InternalServerError(format!("Failed to get contract: {}", e)),
         })?;
 
-    if contract.is_paid {
-        return Err(AppError::BadRequest("Contract is already paid".to_string()));
+    match contract.status {
+        ContractStatus::Signed | ContractStatus::Active => {
+            return Err(AppError::BadRequest("Contract is already paid".to_string()));
+        }
+        ContractStatus::Refunded => {
+            return Err(AppError::BadRequest(
+                "Contract has been refunded".to_string(),
+            ));
+        }
+        ContractStatus::Draft
+        | ContractStatus::AwaitingPayment
+        | ContractStatus::Expired
+        | ContractStatus::Cancelled => {}
     }
 
-    let current_date = Utc::now();
-    // if the contract is expired, create a new contract
-    if contract.end_date <= current_date {
-        // get the outstanding payments
-        let outstanding_payments = payments::check_outstanding_payments(&pool, contract_id)
+    // expired contracts are not re-created here anymore, they have to be renewed explicitly.
+    // The expiry worker may not have caught this contract yet, one that was signed before a
+    // partial refund doesn't expire and can still be paid off.
+    let expired = contract.status == ContractStatus::Expired
+        || (contract.end_date <= Utc::now()
+            && contracts::expire_contract(&pool, contract_id)
+                .await?
+                .is_some());
+    if expired {
+        return Err(ValidationError {
+            code: "contract_expired",
+            message: format!(
+                "Contract has expired and any payments made have been returned, renew it with POST /contract/{}/renew",
+                contract_id
+            ),
+        }
+        .into());
+    }
+
+    // the amounts are checked again with the contract locked, see db::payments
+    match payment_request {
+        PaymentRequest::Installments(installments_payment) => {
+            payments::pay_installment(&pool, contract_id, &installments_payment.amount).await?;
+            Ok((StatusCode::OK, "Payment successful".to_string()))
+        }
+        PaymentRequest::SinglePayment(single_payment) => {
+            payments::pay_in_full(&pool, contract_id, &single_payment.amount).await?;
+            Ok((StatusCode::OK, "Payment successful".to_string()))
+        }
+    }
+}
+
+// Installments past their due date across all clients, oldest first
+pub async fn list_overdue_installments(
+    State(pool): State<Pool<Postgres>>,
+) -> Result<Json<Vec<OverdueInstallment>>, AppError> {
+    Ok(Json(installments::list_overdue(&pool, Utc::now()).await?))
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Deserialize)]
+pub enum RevenueGrouping {
+    #[serde(rename = "product")]
+    Product,
+    #[serde(rename = "segment")]
+    Segment,
+    #[serde(rename = "obligation")]
+    Obligation,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RevenueQuery {
+    product_id: Option<i32>,
+    client_type: Option<ClientKind>,
+    client_id: Option<String>,
+    group_by: Option<RevenueGrouping>,
+    currency: Option<String>,
+}
+
+// Returns the requested currency code and the rate used to convert PLN amounts into it
+fn resolve_exchange_rate(
+    rate_provider: &SharedRateProvider,
+    currency: Option<&str>,
+) -> Result<(String, BigDecimal), AppError> {
+    let currency = currency.unwrap_or(BASE_CURRENCY).to_uppercase();
+    match rate_provider.rate_from_pln(&currency) {
+        Some(rate) => Ok((currency, rate)),
+        None => Err(AppError::BadRequest(format!(
+            "Unsupported currency: {}",
+            currency
+        ))),
+    }
+}
+
+// Checks that the filtered product and client exist and turns the query into a filter
+async fn build_revenue_filter(
+    pool: &Pool<Postgres>,
+    query: &RevenueQuery,
+) -> Result<RevenueFilter, AppError> {
+    if let Some(product_id) = query.product_id {
+        let product_exists = check_if_product_exists(pool, &product_id)
             .await
             .map_err(|e| {
-                AppError::InternalServerError(format!(
-                    "Failed to check outstanding payments: {:?}",
-                    e
-                ))
+                AppError::InternalServerError(format!("Failed to check if product exists: {}", e))
             })?;
+        if !product_exists {
+            return Err(AppError::BadRequest("Product does not exist".to_string()));
+        }
+    }
 
-        payments::create_payment_record_in_db(
-            &pool,
-            contract_id,
-            outstanding_payments
-                * BigDecimal::from_f64(-1.0).expect("Failed to convert -1.0 to BigDecimal"),
-        )
+    let client_id = match (query.client_type, query.client_id.clone()) {
+        (None, None) => None,
+        (Some(client_type), Some(client_id)) => Some(client_type.client_id(client_id)),
+        _ => {
+            return Err(AppError::BadRequest(
+                "client_type and client_id must be given together".to_string(),
+            ))
+        }
+    };
+
+    if let Some(client_id) = &client_id {
+        let client_exists = check_if_client_exists(pool, client_id).await.map_err(|e| {
+            AppError::InternalServerError(format!("Failed to check if client exists: {}", e))
+        })?;
+        if !client_exists {
+            return Err(AppError::BadRequest("Client does not exist".to_string()));
+        }
+    }
+
+    Ok(RevenueFilter {
+        product_id: query.product_id,
+        client_id,
+    })
+}
+
+fn obligation_kind(kind: &str) -> Result<RecognitionKind, AppError> {
+    RecognitionKind::from_db(kind)
+        .ok_or_else(|| AppError::InternalServerError(format!("Unknown obligation kind: {}", kind)))
+}
+
+fn client_segment(contract_type: &str) -> Result<ClientKind, AppError> {
+    ClientKind::from_contract_type(contract_type).ok_or_else(|| {
+        AppError::InternalServerError(format!("Unknown contract type: {}", contract_type))
+    })
+}
+
+pub async fn get_current_revenue(
+    State(pool): State<Pool<Postgres>>,
+    Extension(rate_provider): Extension<SharedRateProvider>,
+    Query(query): Query<RevenueQuery>,
+) -> Result<Json<RevenueReport>, AppError> {
+    let filter = build_revenue_filter(&pool, &query).await?;
+    let (currency, rate) = resolve_exchange_rate(&rate_provider, query.currency.as_deref())?;
+
+    let recognized = revenue::calculate_current_revenue(&pool, &filter).await?;
+
+    let by_product = match query.group_by {
+        Some(RevenueGrouping::Product) => Some(
+            revenue::calculate_revenue_by_product(&pool, &filter)
+                .await?
+                .into_iter()
+                .map(|(product_id, product_name, recognized, _)| ProductRevenue {
+                    product_id,
+                    product_name,
+                    recognized: exchange::convert(&recognized, &rate),
+                })
+                .collect(),
+        ),
+        _ => None,
+    };
+
+    let by_segment = match query.group_by {
+        Some(RevenueGrouping::Segment) => Some(
+            revenue::calculate_revenue_by_segment(&pool, &filter)
+                .await?
+                .into_iter()
+                .map(|(segment, recognized, _)| {
+                    Ok(SegmentRevenue {
+                        segment: client_segment(&segment)?,
+                        recognized: exchange::convert(&recognized, &rate),
+                    })
+                })
+                .collect::<Result<_, AppError>>()?,
+        ),
+        _ => None,
+    };
+
+    let by_obligation = match query.group_by {
+        Some(RevenueGrouping::Obligation) => Some(
+            revenue::calculate_revenue_by_obligation(&pool, &filter)
+                .await?
+                .into_iter()
+                .map(|(kind, recognized, _)| {
+                    Ok(ObligationRevenue {
+                        kind: obligation_kind(&kind)?,
+                        recognized: exchange::convert(&recognized, &rate),
+                    })
+                })
+                .collect::<Result<_, AppError>>()?,
+        ),
+        _ => None,
+    };
+
+    Ok(Json(RevenueReport {
+        recognized: exchange::convert(&recognized, &rate),
+        currency,
+        by_product,
+        by_segment,
+        by_obligation,
+    }))
+}
+
+pub async fn get_predicted_revenue(
+    State(pool): State<Pool<Postgres>>,
+    Extension(rate_provider): Extension<SharedRateProvider>,
+    Query(query): Query<RevenueQuery>,
+) -> Result<Json<PredictedRevenueReport>, AppError> {
+    let filter = build_revenue_filter(&pool, &query).await?;
+    let (currency, rate) = resolve_exchange_rate(&rate_provider, query.currency.as_deref())?;
+
+    let recognized = revenue::calculate_current_revenue(&pool, &filter).await?;
+    let pending = revenue::calculate_pending_revenue(&pool, &filter).await?;
+
+    let by_product = match query.group_by {
+        Some(RevenueGrouping::Product) => Some(
+            revenue::calculate_revenue_by_product(&pool, &filter)
+                .await?
+                .into_iter()
+                .map(|(product_id, product_name, recognized, pending)| {
+                    let (recognized, pending, total) =
+                        exchange::convert_with_total(&recognized, &pending, &rate);
+                    PredictedProductRevenue {
+                        product_id,
+                        product_name,
+                        recognized,
+                        pending,
+                        total,
+                    }
+                })
+                .collect(),
+        ),
+        _ => None,
+    };
+
+    let by_segment = match query.group_by {
+        Some(RevenueGrouping::Segment) => Some(
+            revenue::calculate_revenue_by_segment(&pool, &filter)
+                .await?
+                .into_iter()
+                .map(|(segment, recognized, pending)| {
+                    let (recognized, pending, total) =
+                        exchange::convert_with_total(&recognized, &pending, &rate);
+                    Ok(PredictedSegmentRevenue {
+                        segment: client_segment(&segment)?,
+                        recognized,
+                        pending,
+                        total,
+                    })
+                })
+                .collect::<Result<_, AppError>>()?,
+        ),
+        _ => None,
+    };
+
+    let by_obligation = match query.group_by {
+        Some(RevenueGrouping::Obligation) => Some(
+            revenue::calculate_revenue_by_obligation(&pool, &filter)
+                .await?
+                .into_iter()
+                .map(|(kind, recognized, pending)| {
+                    let (recognized, pending, total) =
+                        exchange::convert_with_total(&recognized, &pending, &rate);
+                    Ok(PredictedObligationRevenue {
+                        kind: obligation_kind(&kind)?,
+                        recognized,
+                        pending,
+                        total,
+                    })
+                })
+                .collect::<Result<_, AppError>>()?,
+        ),
+        _ => None,
+    };
+
+    let (recognized, pending, total) = exchange::convert_with_total(&recognized, &pending, &rate);
+    Ok(Json(PredictedRevenueReport {
+        currency,
+        recognized,
+        pending,
+        total,
+        by_product,
+        by_segment,
+        by_obligation,
+    }))
+}
+
+pub async fn get_revenue_schedule(
+    State(pool): State<Pool<Postgres>>,
+    Path(contract_id): Path<i32>,
+) -> Result<Json<RevenueSchedule>, AppError> {
+    let contract_exists = check_if_contract_exists(&pool, contract_id)
         .await
-        .map_err(|e| AppError::InternalServerError(format!("Failed to create payment: {:?}", e)))?;
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to check if contract exists: {}", e))
+        })?;
+    if !contract_exists {
+        return Err(AppError::BadRequest("Contract does not exist".to_string()));
+    }
+
+    let entries = revenue_schedule::get_schedule_for_contract(&pool, contract_id).await?;
+
+    let current_date = Utc::now();
+    let (recognized, deferred) = entries.iter().fold(
+        (BigDecimal::from(0), BigDecimal::from(0)),
+        |(recognized, deferred), entry| {
+            if entry.recognition_date <= current_date {
+                (recognized + &entry.amount, deferred)
+            } else {
+                (recognized, deferred + &entry.amount)
+            }
+        },
+    );
+
+    Ok(Json(RevenueSchedule {
+        contract_id,
+        recognized,
+        deferred,
+        entries,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeferredRevenueQuery {
+    as_of: NaiveDate,
+}
+
+pub async fn get_deferred_revenue(
+    State(pool): State<Pool<Postgres>>,
+    Query(query): Query<DeferredRevenueQuery>,
+) -> Result<Json<DeferredRevenueReport>, AppError> {
+    let contracts: Vec<DeferredRevenueEntry> =
+        revenue::calculate_deferred_revenue(&pool, query.as_of)
+            .await?
+            .into_iter()
+            .map(
+                |(contract_id, cash_received, recognized)| DeferredRevenueEntry {
+                    contract_id,
+                    deferred: &cash_received - &recognized,
+                    cash_received,
+                    recognized,
+                },
+            )
+            .collect();
+
+    let total_cash_received = contracts.iter().map(|c| c.cash_received.clone()).sum();
+    let total_recognized = contracts.iter().map(|c| c.recognized.clone()).sum();
+    let total_deferred = contracts.iter().map(|c| c.deferred.clone()).sum();
+
+    Ok(Json(DeferredRevenueReport {
+        as_of: query.as_of,
+        contracts,
+        total_cash_received,
+        total_recognized,
+        total_deferred,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct TimeSeriesQuery {
+    from: NaiveDate,
+    to: NaiveDate,
+    granularity: Option<Granularity>,
+}
+
+pub async fn get_revenue_time_series(
+    State(pool): State<Pool<Postgres>>,
+    Query(query): Query<TimeSeriesQuery>,
+) -> Result<Json<RevenueTimeSeries>, AppError> {
+    if query.from > query.to {
+        return Err(AppError::BadRequest(
+            "from must not be later than to".to_string(),
+        ));
+    }
+    let granularity = query.granularity.unwrap_or(Granularity::Month);
+    if revenue::period_count(query.from, query.to, granularity) > revenue::MAX_TIME_SERIES_BUCKETS {
+        return Err(AppError::BadRequest(format!(
+            "Time series can have at most {} periods, narrow the range or use a coarser granularity",
+            revenue::MAX_TIME_SERIES_BUCKETS
+        )));
+    }
+
+    let activity =
+        revenue::calculate_revenue_time_series(&pool, query.from, query.to, granularity).await?;
+
+    // periods without any activity are reported as zero buckets
+    let buckets = revenue::period_starts(query.from, query.to, granularity)
+        .into_iter()
+        .map(|period_start| {
+            let (recognized, cash_collected) = activity
+                .iter()
+                .find(|(start, _, _)| *start == period_start)
+                .map(|(_, recognized, cash_collected)| (recognized.clone(), cash_collected.clone()))
+                .unwrap_or_else(|| (BigDecimal::from(0), BigDecimal::from(0)));
+            RevenueBucket {
+                period_start,
+                period_end: period_start + chrono::Months::new(granularity.months())
+                    - chrono::Days::new(1),
+                recognized,
+                cash_collected,
+            }
+        })
+        .collect();
 
-        let new_contract = create_contract_in_db(
+    Ok(Json(RevenueTimeSeries {
+        granularity,
+        from: query.from,
+        to: query.to,
+        buckets,
+    }))
+}
+
+pub async fn close_accounting_period(
+    State(pool): State<Pool<Postgres>>,
+    Path(period): Path<String>,
+) -> Result<(StatusCode, String), AppError> {
+    let period_start = periods::parse_period(&period).ok_or_else(|| {
+        AppError::BadRequest("Period must be given in the yyyy-mm format".to_string())
+    })?;
+
+    // the current period has to stay open for corrections
+    if period_start >= periods::period_of(&Utc::now()) {
+        return Err(AppError::BadRequest(
+            "Only past accounting periods can be closed".to_string(),
+        ));
+    }
+
+    if !periods::close_period(&pool, period_start).await? {
+        return Err(AppError::BadRequest(format!(
+            "Accounting period {} is already closed",
+            period
+        )));
+    }
+
+    Ok((
+        StatusCode::OK,
+        format!("Accounting period {} closed", period),
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SubscriptionRequest {
+    client_id: ClientId,
+    product_id: i32,
+    name: String,
+    renewal_period_months: i32,
+}
+
+pub async fn create_subscription(
+    State(pool): State<Pool<Postgres>>,
+    Json(subscription_request): Json<SubscriptionRequest>,
+) -> Result<(StatusCode, Json<Subscription>), AppError> {
+    subscriptions::validate_renewal_period(subscription_request.renewal_period_months)
+        .map_err(AppError::BadRequest)?;
+
+    if subscription_request.name.trim().is_empty() {
+        return Err(AppError::BadRequest(
+            "Subscription name must not be empty".to_string(),
+        ));
+    }
+
+    let (product_exists, client_exists) = check_product_and_client_exist(
+        &pool,
+        subscription_request.product_id,
+        subscription_request.client_id.clone(),
+    )
+    .await
+    .map_err(|e| {
+        AppError::InternalServerError(format!(
+            "Failed to check if product and client exist: {}",
+            e
+        ))
+    })?;
+
+    if !product_exists {
+        return Err(AppError::BadRequest("Product does not exist".to_string()));
+    }
+    if !client_exists {
+        return Err(AppError::BadRequest("Client does not exist".to_string()));
+    }
+
+    let has_subscription = subscriptions::check_if_client_has_active_subscription(
+        &pool,
+        &subscription_request.client_id,
+        subscription_request.product_id,
+    )
+    .await?;
+    if has_subscription {
+        return Err(AppError::BadRequest(
+            "Client already has an active subscription for this product".to_string(),
+        ));
+    }
+
+    // the price of a single renewal period is taken from the catalog
+    let price = get_price_for_product(&pool, subscription_request.product_id)
+        .await
+        .map_err(|e| AppError::InternalServerError(format!("Failed to get price: {:?}", e)))?;
+
+    let subscription = subscriptions::create_subscription(
+        &pool,
+        subscription_request.name.trim(),
+        &subscription_request.client_id,
+        subscription_request.product_id,
+        subscription_request.renewal_period_months,
+        &price,
+    )
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(subscription)))
+}
+
+pub async fn cancel_subscription(
+    State(pool): State<Pool<Postgres>>,
+    Path(subscription_id): Path<i32>,
+    Json(client_id): Json<ClientId>,
+) -> Result<(StatusCode, String), AppError> {
+    let db_error = |e: sqlx::Error| {
+        AppError::InternalServerError(format!("Failed to cancel subscription: {}", e))
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    let subscription = subscriptions::lock_subscription(&mut tx, &client_id, subscription_id)
+        .await?
+        .ok_or_else(|| {
+            AppError::BadRequest(
+                "Subscription does not exist or does not belong to this client".to_string(),
+            )
+        })?;
+
+    if subscription.status == SubscriptionStatus::Cancelled {
+        return Err(AppError::BadRequest(
+            "Subscription is already cancelled".to_string(),
+        ));
+    }
+
+    subscriptions::cancel_subscription(&mut tx, subscription_id).await?;
+    tx.commit().await.map_err(db_error)?;
+
+    Ok((StatusCode::OK, "Subscription cancelled".to_string()))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SubscriptionPaymentRequest {
+    client_id: ClientId,
+    amount: BigDecimal,
+}
+
+pub async fn create_subscription_payment(
+    State(pool): State<Pool<Postgres>>,
+    Path(subscription_id): Path<i32>,
+    Json(payment_request): Json<SubscriptionPaymentRequest>,
+) -> Result<(StatusCode, String), AppError> {
+    let db_error = |e: sqlx::Error| {
+        AppError::InternalServerError(format!("Failed to pay for subscription: {}", e))
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    let subscription =
+        subscriptions::lock_subscription(&mut tx, &payment_request.client_id, subscription_id)
+            .await?
+            .ok_or_else(|| {
+                AppError::BadRequest(
+                    "Subscription does not exist or does not belong to this client".to_string(),
+                )
+            })?;
+
+    if subscription.status == SubscriptionStatus::Cancelled {
+        return Err(AppError::BadRequest(
+            "Subscription is cancelled".to_string(),
+        ));
+    }
+
+    let now = Utc::now();
+    let period_number = subscriptions::current_period_number(&subscription, now);
+    let paid_periods = subscriptions::get_paid_periods(&mut tx, subscription_id).await?;
+
+    // the background worker cancels it, until then it just can't be paid for anymore
+    if subscriptions::has_lapsed(&subscription, &paid_periods, now) {
+        return Err(AppError::BadRequest(
+            "Subscription lapsed because a renewal period was not paid".to_string(),
+        ));
+    }
+
+    if paid_periods.contains(&period_number) {
+        return Err(AppError::BadRequest(
+            "Current renewal period has already been paid".to_string(),
+        ));
+    }
+
+    let discount = if period_number == 0 {
+        find_discounts_for_client(
             &pool,
-            &contract.price,
-            &contract.product_id,
-            &client_id,
-            &contract.start_date,
-            &contract.end_date,
-            &contract.years_supported,
+            subscription.product_id,
+            payment_request.client_id.clone(),
         )
         .await
-        .map_err(|e| AppError::InternalServerError(format!("Failed to create contract: {}", e)))?;
+        .map_err(|e| AppError::InternalServerError(format!("Failed to get discount: {}", e)))?
+        .unwrap_or(BigDecimal::from(0))
+    } else {
+        BigDecimal::from(0)
+    };
+    let renewal_amount =
+        subscriptions::amount_due_for_period(&subscription.price, &discount, period_number);
+
+    // what is still owed for earlier plan changes is paid together with the renewal
+    let charges = subscriptions::get_outstanding_charges(&mut tx, subscription_id).await?;
+    let amount_due = charges
+        .iter()
+        .fold(renewal_amount.clone(), |total, (_, _, amount)| {
+            total + amount
+        });
+
+    if payment_request.amount != amount_due {
+        return Err(AppError::BadRequest(format!(
+            "Amount does not match the amount due for the current renewal period: {}",
+            amount_due
+        )));
+    }
+
+    payments::create_subscription_payment_record_in_db(
+        &mut tx,
+        subscription_id,
+        period_number,
+        SubscriptionPaymentKind::Renewal,
+        renewal_amount,
+    )
+    .await?;
+    subscriptions::settle_charges(&mut tx, subscription_id, charges).await?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    Ok((StatusCode::OK, "Payment successful".to_string()))
+}
 
-        return Ok((
-            StatusCode::CREATED,
-            "Missed payment, a new contract has been created. Any outstanding payments have been returned to you.".to_string(),
+#[derive(serde::Deserialize)]
+pub struct SubscriptionChangeRequest {
+    client_id: ClientId,
+    product_id: Option<i32>,
+    renewal_period_months: Option<i32>,
+}
+
+pub async fn change_subscription_plan(
+    State(pool): State<Pool<Postgres>>,
+    Path(subscription_id): Path<i32>,
+    Json(change_request): Json<SubscriptionChangeRequest>,
+) -> Result<Json<SubscriptionPlanChange>, AppError> {
+    let db_error = |e: sqlx::Error| {
+        AppError::InternalServerError(format!("Failed to change subscription plan: {}", e))
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    let subscription =
+        subscriptions::lock_subscription(&mut tx, &change_request.client_id, subscription_id)
+            .await?
+            .ok_or_else(|| {
+                AppError::BadRequest(
+                    "Subscription does not exist or does not belong to this client".to_string(),
+                )
+            })?;
+
+    if subscription.status == SubscriptionStatus::Cancelled {
+        return Err(AppError::BadRequest(
+            "Subscription is cancelled".to_string(),
         ));
     }
 
-    match payment_request {
-        PaymentRequest::Installments(installments_payment) => {
-            let outstanding_payments = payments::check_outstanding_payments(&pool, contract_id)
-                .await
-                .map_err(|e| {
-                    AppError::InternalServerError(format!(
-                        "Failed to check outstanding payments: {:?}",
-                        e
-                    ))
-                })?;
-
-            if installments_payment.amount.clone() > outstanding_payments {
-                return Err(AppError::BadRequest(
-                    "Amount is greater than outstanding payments".to_string(),
-                ));
-            }
+    let product_id = change_request.product_id.unwrap_or(subscription.product_id);
+    let renewal_period_months = change_request
+        .renewal_period_months
+        .unwrap_or(subscription.renewal_period_months);
+    subscriptions::validate_renewal_period(renewal_period_months).map_err(AppError::BadRequest)?;
 
-            // Create a payment entry in the database
-            pay_for_contract(
-                &pool,
-                contract_id,
-                &client_id,
-                installments_payment.amount.clone(),
-            )
+    if product_id == subscription.product_id
+        && renewal_period_months == subscription.renewal_period_months
+    {
+        return Err(AppError::BadRequest(
+            "New plan is the same as the current one".to_string(),
+        ));
+    }
+
+    if product_id != subscription.product_id {
+        let product_exists = check_if_product_exists(&pool, &product_id)
             .await
             .map_err(|e| {
-                AppError::InternalServerError(format!("Failed to pay for contract: {:?}", e))
+                AppError::InternalServerError(format!("Failed to check if product exists: {}", e))
             })?;
+        if !product_exists {
+            return Err(AppError::BadRequest("Product does not exist".to_string()));
+        }
 
-            // If the payment is the full amount, handle the full payment and set the contract to paid =>'signed'
-            if installments_payment.amount.clone() == outstanding_payments {
-                payments::handle_full_payment(&pool, contract_id, client_id)
-                    .await
-                    .map_err(|e| {
-                        AppError::InternalServerError(format!(
-                            "Failed to handle full payment: {:?}",
-                            e
-                        ))
-                    })?;
-                return Ok((StatusCode::OK, "Payment successful".to_string()));
-            }
+        let has_subscription = subscriptions::check_if_client_has_active_subscription(
+            &pool,
+            &change_request.client_id,
+            product_id,
+        )
+        .await?;
+        if has_subscription {
+            return Err(AppError::BadRequest(
+                "Client already has an active subscription for this product".to_string(),
+            ));
+        }
+    }
 
-            Ok((StatusCode::OK, "Payment successful".to_string()))
+    let now = Utc::now();
+    if now < subscription.billing_anchor {
+        return Err(AppError::BadRequest(
+            "Plan can only be changed once per renewal period".to_string(),
+        ));
+    }
+
+    let period_number = subscriptions::current_period_number(&subscription, now);
+    let paid_periods = subscriptions::get_paid_periods(&mut tx, subscription_id).await?;
+    if !paid_periods.contains(&period_number) {
+        return Err(AppError::BadRequest(
+            "Current renewal period has to be paid before changing the plan".to_string(),
+        ));
+    }
+
+    let (period_start, period_end) = subscriptions::period_bounds(
+        subscription.billing_anchor,
+        subscription.renewal_period_months,
+        period_number - subscription.period_offset,
+    )
+    .ok_or_else(|| AppError::InternalServerError("Renewal period is out of range".to_string()))?;
+    let remaining_seconds = (period_end - now).num_seconds();
+
+    // the unused part of what was paid for the current period goes back to the client
+    let paid_amount =
+        subscriptions::get_paid_amount_for_period(&mut tx, subscription_id, period_number).await?;
+    let credited = subscriptions::prorate(
+        &paid_amount,
+        remaining_seconds,
+        (period_end - period_start).num_seconds(),
+    );
+
+    // the rest of the current period is billed at the new plan's rate
+    let new_price = get_price_for_product(&pool, product_id)
+        .await
+        .map_err(|e| AppError::InternalServerError(format!("Failed to get price: {:?}", e)))?;
+    let (new_period_start, new_period_end) =
+        subscriptions::period_bounds(now, renewal_period_months, 0).ok_or_else(|| {
+            AppError::InternalServerError("Renewal period is out of range".to_string())
+        })?;
+    let charged = subscriptions::prorate(
+        &new_price,
+        remaining_seconds,
+        (new_period_end - new_period_start).num_seconds(),
+    );
+
+    payments::create_subscription_payment_record_in_db(
+        &mut tx,
+        subscription_id,
+        period_number,
+        SubscriptionPaymentKind::ProrationCredit,
+        -credited.clone(),
+    )
+    .await?;
+    // the client hasn't paid for the new plan yet, the charge is due with the next renewal
+    if charged > BigDecimal::from(0) {
+        subscriptions::add_charge(&mut tx, subscription_id, period_number, &charged).await?;
+    }
+
+    // the new plan's renewal periods start where the current period would have ended
+    subscriptions::change_plan(
+        &mut tx,
+        subscription_id,
+        product_id,
+        renewal_period_months,
+        &new_price,
+        period_end,
+        period_number + 1,
+    )
+    .await?;
+
+    let subscription =
+        subscriptions::lock_subscription(&mut tx, &change_request.client_id, subscription_id)
+            .await?
+            .ok_or_else(|| AppError::InternalServerError("Subscription disappeared".to_string()))?;
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(Json(SubscriptionPlanChange {
+        subscription,
+        credited,
+        charged,
+    }))
+}
+
+// Contract together with its payments, balance and installment plan
+async fn contract_details(
+    pool: &Pool<Postgres>,
+    contract: Contract,
+) -> Result<ContractDetails, AppError> {
+    let payments = get_payments_for_contract(pool, contract.id).await?;
+    let balance = contracts::balance_from_payments(&contract, &payments);
+    let installments = installments::get_installments_for_contract(pool, contract.id).await?;
+    Ok(ContractDetails {
+        contract,
+        payments,
+        balance,
+        installments,
+    })
+}
+
+pub async fn get_contract(
+    State(pool): State<Pool<Postgres>>,
+    Path(contract_id): Path<i32>,
+) -> Result<Json<ContractDetails>, AppError> {
+    let contract = contracts::get_contract(&pool, contract_id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Contract does not exist".to_string()))?;
+
+    Ok(Json(contract_details(&pool, contract).await?))
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(serde::Deserialize)]
+pub struct ContractListQuery {
+    status: Option<ContractStatus>,
+    // inclusive range for the contract start date
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    // pages are counted from 1
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+pub async fn list_client_contracts(
+    State(pool): State<Pool<Postgres>>,
+    Path((client_type, client_id)): Path<(ClientKind, String)>,
+    Query(query): Query<ContractListQuery>,
+) -> Result<Json<ContractPage>, AppError> {
+    let client_id = client_type.client_id(client_id);
+
+    let client_exists = check_if_client_exists(&pool, &client_id)
+        .await
+        .map_err(|e| {
+            AppError::InternalServerError(format!("Failed to check if client exists: {}", e))
+        })?;
+    if !client_exists {
+        return Err(AppError::BadRequest("Client does not exist".to_string()));
+    }
+
+    let page = query.page.unwrap_or(1);
+    if page < 1 {
+        return Err(AppError::BadRequest("page must be at least 1".to_string()));
+    }
+    let per_page = query.per_page.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&per_page) {
+        return Err(AppError::BadRequest(format!(
+            "per_page must be between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(AppError::BadRequest(
+                "from must not be after to".to_string(),
+            ));
         }
-        PaymentRequest::SinglePayment(single_payment) => {
-            if single_payment.amount != contract.price {
-                return Err(AppError::BadRequest(
-                    "Amount does not match contract price".to_string(),
-                ));
-            }
+    }
 
-            pay_for_contract(&pool, contract_id, &client_id, single_payment.amount)
-                .await
-                .map_err(|e| {
-                    AppError::InternalServerError(format!("Failed to pay for contract: {:?}", e))
-                })?;
+    let filter = ContractFilter {
+        status: query.status,
+        from: query.from,
+        to: query.to,
+    };
+    let (found, total) = contracts::list_contracts_for_client(
+        &pool,
+        &client_id,
+        &filter,
+        per_page,
+        (page - 1) * per_page,
+    )
+    .await?;
+
+    let mut details = Vec::with_capacity(found.len());
+    for contract in found {
+        details.push(contract_details(&pool, contract).await?);
+    }
 
-            payments::handle_full_payment(&pool, contract_id, client_id)
-                .await
-                .map_err(|e| {
-                    AppError::InternalServerError(format!("Failed to handle full payment: {:?}", e))
-                })?;
+    Ok(Json(ContractPage {
+        contracts: details,
+        page,
+        per_page,
+        total,
+    }))
+}
 
-            Ok((StatusCode::OK, "Payment successful".to_string()))
+#[derive(serde::Deserialize)]
+pub struct CancelContractRequest {
+    client_id: ClientId,
+    reason: Option<String>,
+}
+
+pub async fn cancel_contract(
+    State(pool): State<Pool<Postgres>>,
+    Path(contract_id): Path<i32>,
+    Json(cancel_request): Json<CancelContractRequest>,
+) -> Result<Json<RefundSummary>, AppError> {
+    let contract = contracts::get_contract(&pool, contract_id)
+        .await?
+        .filter(|contract| contract.client_id == cancel_request.client_id)
+        .ok_or_else(|| {
+            AppError::BadRequest(
+                "Contract does not exist or does not belong to this client".to_string(),
+            )
+        })?;
+
+    if contract.status == ContractStatus::Cancelled {
+        return Err(AppError::BadRequest(
+            "Contract is already cancelled".to_string(),
+        ));
+    }
+    // check before any money is moved, the transition itself checks again
+    if !contracts::is_allowed_transition(contract.status, ContractStatus::Cancelled) {
+        return Err(AppError::BadRequest(format!(
+            "Contract is {} and cannot be cancelled",
+            contract.status.as_str()
+        )));
+    }
+
+    let reason = cancel_request
+        .reason
+        .unwrap_or_else(|| "Contract cancelled".to_string());
+    let amount_paid = contracts::cancel_contract(&pool, contract_id, &reason).await?;
+    let amount_refunded = if amount_paid > BigDecimal::from(0) {
+        amount_paid.clone()
+    } else {
+        BigDecimal::from(0)
+    };
+
+    Ok(Json(RefundSummary {
+        contract_id,
+        status: ContractStatus::Cancelled,
+        amount_paid,
+        amount_refunded,
+        reason,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct RefundPaymentRequest {
+    client_id: ClientId,
+    // the rest of the payment when left out
+    amount: Option<BigDecimal>,
+    reason: String,
+}
+
+pub async fn refund_payment(
+    State(pool): State<Pool<Postgres>>,
+    Path(payment_id): Path<i32>,
+    Json(refund_request): Json<RefundPaymentRequest>,
+) -> Result<(StatusCode, Json<PaymentRefund>), AppError> {
+    let reason = refund_request.reason.trim();
+    if reason.is_empty() {
+        return Err(ValidationError {
+            code: "refund_reason_required",
+            message: "A reason is required for every refund".to_string(),
         }
+        .into());
+    }
+
+    let contract_id = payments::get_payment_contract_id(&pool, payment_id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Payment does not exist".to_string()))?;
+    contracts::get_contract(&pool, contract_id)
+        .await?
+        .filter(|contract| contract.client_id == refund_request.client_id)
+        .ok_or_else(|| {
+            AppError::BadRequest(
+                "Payment does not exist or does not belong to this client".to_string(),
+            )
+        })?;
+
+    let refund =
+        payments::refund_payment(&pool, payment_id, refund_request.amount.as_ref(), reason).await?;
+    Ok((StatusCode::CREATED, Json(refund)))
+}
+
+#[derive(serde::Deserialize)]
+pub struct RenewContractRequest {
+    client_id: ClientId,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // defaults to the support period of the expired contract
+    years_supported: Option<i32>,
+}
+
+// Creates a new contract for an expired one, priced at today's prices and discounts
+pub async fn renew_contract(
+    State(pool): State<Pool<Postgres>>,
+    Path(contract_id): Path<i32>,
+    Json(renew_request): Json<RenewContractRequest>,
+) -> Result<(StatusCode, Json<ContractDetails>), AppError> {
+    let contract = contracts::get_contract(&pool, contract_id)
+        .await?
+        .filter(|contract| contract.client_id == renew_request.client_id)
+        .ok_or_else(|| {
+            AppError::BadRequest(
+                "Contract does not exist or does not belong to this client".to_string(),
+            )
+        })?;
+
+    let mut status = contract.status;
+    // the expiry worker may not have caught this contract yet
+    if matches!(
+        status,
+        ContractStatus::Draft | ContractStatus::AwaitingPayment
+    ) && contract.end_date <= Utc::now()
+        && contracts::expire_contract(&pool, contract_id)
+            .await?
+            .is_some()
+    {
+        status = ContractStatus::Expired;
+    }
+
+    if status != ContractStatus::Expired {
+        return Err(AppError::BadRequest(format!(
+            "Only expired contracts can be renewed, this contract is {}",
+            status.as_str()
+        )));
+    }
+
+    contracts::validate_signing_window(&renew_request.start_date, &renew_request.end_date)?;
+
+    let price = pricing::price_contract(
+        &pool,
+        contract.product_id,
+        renew_request
+            .years_supported
+            .unwrap_or(contract.years_supported),
+        &renew_request.client_id,
+    )
+    .await?;
+
+    let db_error =
+        |e: sqlx::Error| AppError::InternalServerError(format!("Failed to renew contract: {}", e));
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    // concurrent renewals of the same contract wait here and then see the first one
+    contracts::lock_for_renewal(&mut tx, contract_id).await?;
+
+    // a previous renewal may still be open or already paid
+    let client_has_contract = check_if_client_has_contract_for_product(
+        &mut tx,
+        renew_request.client_id.clone(),
+        contract.product_id,
+    )
+    .await
+    .map_err(|e| {
+        AppError::InternalServerError(format!("Failed to check if client has contract: {}", e))
+    })?;
+    if client_has_contract {
+        return Err(AppError::BadRequest(
+            "Client already has contract for this product".to_string(),
+        ));
     }
+
+    let renewed_id = create_contract_in_db(
+        &mut tx,
+        &price,
+        &contract.product_id,
+        &renew_request.client_id,
+        &renew_request.start_date,
+        &renew_request.end_date,
+        Some(contract_id),
+    )
+    .await?;
+    tx.commit().await.map_err(db_error)?;
+
+    let renewed = contracts::get_contract(&pool, renewed_id)
+        .await?
+        .ok_or_else(|| AppError::InternalServerError("Renewed contract disappeared".to_string()))?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(contract_details(&pool, renewed).await?),
+    ))
 }
@@ -1,18 +1,24 @@
 use axum::{
-    routing::{delete, get, post},
-    Router,
+    routing::{delete, get, post, put},
+    Extension, Router,
 };
+use std::sync::Arc;
 
 mod client;
 
 mod db;
 use db::connect_db;
 
+mod exchange;
+use exchange::{JsonFileRateProvider, SharedRateProvider};
+
 #[cfg(test)]
 mod tests;
 
 mod handler;
 
+mod worker;
+
 #[tokio::main]
 async fn main() {
     // initialize tracing
@@ -24,6 +30,20 @@ async fn main() {
         .await
         .expect("Failed to run migrations");
 
+    let rates_path =
+        std::env::var("EXCHANGE_RATES_PATH").unwrap_or_else(|_| "rates.json".to_string());
+    let rate_provider: SharedRateProvider = Arc::new(
+        JsonFileRateProvider::from_file(&rates_path).expect("Failed to load exchange rates"),
+    );
+
+    let expiry_interval = worker::parse_expiry_interval(
+        std::env::var("CONTRACT_EXPIRY_INTERVAL_SECS")
+            .ok()
+            .as_deref(),
+    )
+    .expect("Failed to read contract expiry interval");
+    worker::spawn_expiry(pool.clone(), expiry_interval);
+
     // build our application with a route
     let app = Router::new()
         .route("/health", get(|| async { "Status: OK" }))
@@ -33,9 +53,65 @@ async fn main() {
         .route("/client", delete(handler::delete_client))
         // PUT /client
         .route("/client", put(handler::update_client))
+        // GET /client/{individual|company}/{id}/contracts?status=&from=&to=&page=&per_page=
+        .route(
+            "/client/{type}/{id}/contracts",
+            get(handler::list_client_contracts),
+        )
         // POST /contract
         .route("/contract", post(handler::create_contract))
+        // POST /contract/quote
+        .route("/contract/quote", post(handler::quote_contract))
+        // GET /contract/{id}
+        .route("/contract/{id}", get(handler::get_contract))
+        // POST /contract/{id}/cancel
+        .route("/contract/{id}/cancel", post(handler::cancel_contract))
+        // POST /contract/{id}/renew
+        .route("/contract/{id}/renew", post(handler::renew_contract))
+        // GET /contract/{id}/revenue-schedule
+        .route(
+            "/contract/{id}/revenue-schedule",
+            get(handler::get_revenue_schedule),
+        )
         .route("/payment", post(handler::create_payment))
+        // POST /payment/{id}/refund
+        .route("/payment/{id}/refund", post(handler::refund_payment))
+        // GET /installments/overdue
+        .route(
+            "/installments/overdue",
+            get(handler::list_overdue_installments),
+        )
+        // POST /subscription
+        .route("/subscription", post(handler::create_subscription))
+        // POST /subscription/{id}/cancel
+        .route(
+            "/subscription/{id}/cancel",
+            post(handler::cancel_subscription),
+        )
+        // POST /subscription/{id}/payment
+        .route(
+            "/subscription/{id}/payment",
+            post(handler::create_subscription_payment),
+        )
+        // POST /subscription/{id}/change
+        .route(
+            "/subscription/{id}/change",
+            post(handler::change_subscription_plan),
+        )
+        // POST /periods/{yyyy-mm}/close
+        .route(
+            "/periods/{period}/close",
+            post(handler::close_accounting_period),
+        )
+        // GET /revenue/current
+        .route("/revenue/current", get(handler::get_current_revenue))
+        // GET /revenue/predicted
+        .route("/revenue/predicted", get(handler::get_predicted_revenue))
+        // GET /revenue/deferred?as_of=YYYY-MM-DD
+        .route("/revenue/deferred", get(handler::get_deferred_revenue))
+        // GET /revenue/timeseries?from=&to=&granularity=month|quarter|year
+        .route("/revenue/timeseries", get(handler::get_revenue_time_series))
+        .layer(Extension(rate_provider))
         .with_state(pool);
 
     // run our app with hyper, listening globally on port 3000
@@ -1,7 +1,7 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use super::*;
-    use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
+    use bigdecimal::BigDecimal;
     use std::str::FromStr;
 
     // First, you'll need to extract these pure functions from your existing code:
@@ -305,4 +305,2051 @@ mod tests {
         assert!(precise_result > bd("100"));
         assert!(precise_result < bd("110"));
     }
+
+    #[test]
+    fn test_json_file_exchange_rates() {
+        use crate::exchange::{ExchangeRateProvider, JsonFileRateProvider};
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/rates.json");
+        let provider = JsonFileRateProvider::from_file(path).unwrap();
+
+        // Base currency is always available
+        assert_eq!(provider.rate_from_pln("PLN"), Some(bd("1")));
+        assert_eq!(provider.rate_from_pln("pln"), Some(bd("1")));
+
+        // Rates from the stub file, case insensitive
+        assert_eq!(provider.rate_from_pln("EUR"), Some(bd("0.2340")));
+        assert_eq!(provider.rate_from_pln("usd"), Some(bd("0.2530")));
+
+        // Unknown currency
+        assert_eq!(provider.rate_from_pln("GBP"), None);
+
+        // Invalid files
+        assert!(JsonFileRateProvider::from_json(r#"{"base": "EUR", "rates": {}}"#).is_err());
+        assert!(
+            JsonFileRateProvider::from_json(r#"{"base": "PLN", "rates": {"EUR": "abc"}}"#).is_err()
+        );
+        assert!(
+            JsonFileRateProvider::from_json(r#"{"base": "PLN", "rates": {"EUR": "-0.2"}}"#)
+                .is_err()
+        );
+        assert!(JsonFileRateProvider::from_file("does-not-exist.json").is_err());
+    }
+
+    #[test]
+    fn test_currency_conversion() {
+        use crate::exchange::{convert, convert_with_total};
+
+        // Identity
+        assert_eq!(convert(&bd("1234.56"), &bd("1")), bd("1234.56"));
+
+        // Rounded to two decimal places
+        assert_eq!(convert(&bd("1000.00"), &bd("0.2340")), bd("234.00"));
+        assert_eq!(convert(&bd("99.99"), &bd("0.2530")), bd("25.30"));
+        assert_eq!(convert(&bd("0.02"), &bd("0.25")), bd("0.01"));
+
+        // No f64 precision loss on large amounts
+        assert_eq!(convert(&bd("12345678.91"), &bd("0.2340")), bd("2888888.86"));
+
+        // The total adds up the rounded parts instead of rounding the sum on its own
+        assert_eq!(
+            convert_with_total(&bd("0.01"), &bd("0.01"), &bd("0.5")),
+            (bd("0.01"), bd("0.01"), bd("0.02"))
+        );
+        assert_eq!(convert(&bd("0.02"), &bd("0.5")), bd("0.01"));
+    }
+
+    #[test]
+    fn test_performance_obligation_allocation() {
+        use crate::client::{RecognitionKind, RecognitionRule};
+        use crate::db::obligations::allocate_obligations;
+
+        // License of 2 000 zł and one year of support (1 000 zł) sold for 2 700 zł
+        let obligations = allocate_obligations(&bd("2700.00"), &bd("2000.00"), 1);
+        assert_eq!(obligations.len(), 2);
+        assert_eq!(obligations[0].kind, RecognitionKind::License);
+        assert_eq!(obligations[0].support_year, None);
+        assert_eq!(obligations[0].allocated_price, bd("1800.00"));
+        assert_eq!(
+            obligations[0].recognition_rule,
+            RecognitionRule::PointInTime
+        );
+        assert_eq!(obligations[1].kind, RecognitionKind::Support);
+        assert_eq!(obligations[1].support_year, Some(1));
+        assert_eq!(obligations[1].allocated_price, bd("900.00"));
+        assert_eq!(obligations[1].recognition_rule, RecognitionRule::Ratably);
+
+        // One obligation per support year, rounding leftovers go to the last year
+        let obligations = allocate_obligations(&bd("1000.00"), &bd("0.00"), 3);
+        assert_eq!(obligations.len(), 4);
+        assert_eq!(obligations[0].allocated_price, bd("0.00"));
+        assert_eq!(obligations[1].allocated_price, bd("333.33"));
+        assert_eq!(obligations[2].allocated_price, bd("333.33"));
+        assert_eq!(obligations[3].allocated_price, bd("333.34"));
+        assert_eq!(obligations[3].support_year, Some(3));
+
+        // Allocated prices always add up to the contract price
+        let obligations = allocate_obligations(&bd("4321.99"), &bd("1234.56"), 2);
+        let total: BigDecimal = obligations.iter().map(|o| o.allocated_price.clone()).sum();
+        assert_eq!(total, bd("4321.99"));
+
+        // Without support the license takes the whole price
+        let obligations = allocate_obligations(&bd("999.99"), &bd("1000.00"), 0);
+        assert_eq!(obligations.len(), 1);
+        assert_eq!(obligations[0].allocated_price, bd("999.99"));
+    }
+
+    #[test]
+    fn test_revenue_schedule_generation() {
+        use crate::client::RecognitionKind;
+        use crate::db::obligations::allocate_obligations;
+        use crate::db::revenue_schedule::build_revenue_schedule;
+        use chrono::{TimeZone, Utc};
+
+        let paid_at = Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap();
+
+        // License of 2 000 zł and one year of support (1 000 zł) sold for 2 700 zł
+        let obligations = allocate_obligations(&bd("2700.00"), &bd("2000.00"), 1);
+        let schedule = build_revenue_schedule(&obligations, paid_at);
+        assert_eq!(schedule.len(), 13);
+        assert_eq!(schedule[0].kind, RecognitionKind::License);
+        assert_eq!(schedule[0].amount, bd("1800.00"));
+        assert_eq!(schedule[0].recognition_date, paid_at);
+
+        // Support is spread evenly over twelve months
+        assert!(schedule[1..]
+            .iter()
+            .all(|entry| entry.kind == RecognitionKind::Support && entry.amount == bd("75.00")));
+        assert_eq!(
+            schedule[1].recognition_date,
+            Utc.with_ymd_and_hms(2024, 2, 29, 12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            schedule[12].recognition_date,
+            Utc.with_ymd_and_hms(2025, 1, 31, 12, 0, 0).unwrap()
+        );
+
+        // Every support year is spread over its own twelve months
+        let obligations = allocate_obligations(&bd("1000.00"), &bd("0.00"), 3);
+        let schedule = build_revenue_schedule(&obligations, paid_at);
+        assert_eq!(schedule.len(), 37);
+        assert_eq!(schedule[0].amount, bd("0.00"));
+        assert_eq!(schedule[1].amount, bd("27.77"));
+        assert_eq!(schedule[12].amount, bd("27.86"));
+        assert_eq!(schedule[36].amount, bd("27.87"));
+        assert_eq!(
+            schedule[36].recognition_date,
+            Utc.with_ymd_and_hms(2027, 1, 31, 12, 0, 0).unwrap()
+        );
+        let total: BigDecimal = schedule.iter().map(|entry| entry.amount.clone()).sum();
+        assert_eq!(total, bd("1000.00"));
+
+        // Without support everything is recognized on payment
+        let obligations = allocate_obligations(&bd("999.99"), &bd("1000.00"), 0);
+        let schedule = build_revenue_schedule(&obligations, paid_at);
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(schedule[0].amount, bd("999.99"));
+    }
+
+    #[test]
+    fn test_revenue_time_series_periods() {
+        use crate::client::Granularity;
+        use crate::db::revenue::{period_count, period_start, period_starts};
+        use chrono::NaiveDate;
+
+        fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+            NaiveDate::from_ymd_opt(y, m, d).unwrap()
+        }
+
+        // Truncation to the start of the period
+        assert_eq!(
+            period_start(date(2024, 5, 17), Granularity::Month),
+            date(2024, 5, 1)
+        );
+        assert_eq!(
+            period_start(date(2024, 5, 17), Granularity::Quarter),
+            date(2024, 4, 1)
+        );
+        assert_eq!(
+            period_start(date(2024, 12, 31), Granularity::Quarter),
+            date(2024, 10, 1)
+        );
+        assert_eq!(
+            period_start(date(2024, 5, 17), Granularity::Year),
+            date(2024, 1, 1)
+        );
+
+        // Every period in the range is present, including the partial first and last ones
+        assert_eq!(
+            period_starts(date(2024, 1, 15), date(2024, 4, 2), Granularity::Month),
+            vec![
+                date(2024, 1, 1),
+                date(2024, 2, 1),
+                date(2024, 3, 1),
+                date(2024, 4, 1)
+            ]
+        );
+        assert_eq!(
+            period_starts(date(2024, 2, 1), date(2025, 1, 1), Granularity::Quarter),
+            vec![
+                date(2024, 1, 1),
+                date(2024, 4, 1),
+                date(2024, 7, 1),
+                date(2024, 10, 1),
+                date(2025, 1, 1)
+            ]
+        );
+        assert_eq!(
+            period_starts(date(2023, 6, 1), date(2024, 6, 1), Granularity::Year),
+            vec![date(2023, 1, 1), date(2024, 1, 1)]
+        );
+
+        // Single day range
+        assert_eq!(
+            period_starts(date(2024, 3, 3), date(2024, 3, 3), Granularity::Month),
+            vec![date(2024, 3, 1)]
+        );
+
+        // Counted without building the periods, so oversized ranges can be rejected up front
+        for (from, to, granularity) in [
+            (date(2024, 1, 15), date(2024, 4, 2), Granularity::Month),
+            (date(2024, 2, 1), date(2025, 1, 1), Granularity::Quarter),
+            (date(2023, 6, 1), date(2024, 6, 1), Granularity::Year),
+            (date(2024, 3, 3), date(2024, 3, 3), Granularity::Month),
+        ] {
+            assert_eq!(
+                period_count(from, to, granularity) as usize,
+                period_starts(from, to, granularity).len()
+            );
+        }
+        assert_eq!(
+            period_count(date(1950, 1, 1), date(2049, 12, 31), Granularity::Month),
+            1200
+        );
+        assert_eq!(
+            period_count(date(2024, 5, 1), date(2024, 4, 1), Granularity::Month),
+            0
+        );
+    }
+
+    #[test]
+    fn test_accounting_period_parsing() {
+        use crate::db::periods::{parse_period, period_of};
+        use chrono::{NaiveDate, TimeZone, Utc};
+
+        // Valid periods
+        assert_eq!(parse_period("2024-05"), NaiveDate::from_ymd_opt(2024, 5, 1));
+        assert_eq!(
+            parse_period("2023-12"),
+            NaiveDate::from_ymd_opt(2023, 12, 1)
+        );
+
+        // Invalid periods
+        assert_eq!(parse_period("2024-13"), None);
+        assert_eq!(parse_period("2024-00"), None);
+        assert_eq!(parse_period("2024-5"), None);
+        assert_eq!(parse_period("24-05"), None);
+        assert_eq!(parse_period("2024-05-01"), None);
+        assert_eq!(parse_period("may-2024"), None);
+        assert_eq!(parse_period("2024-+5"), None);
+        assert_eq!(parse_period(""), None);
+
+        // Any moment belongs to the period of its month
+        let date = Utc.with_ymd_and_hms(2024, 2, 29, 23, 59, 59).unwrap();
+        assert_eq!(
+            period_of(&date),
+            NaiveDate::from_ymd_opt(2024, 2, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_subscription_renewal_period_validation() {
+        use crate::db::subscriptions::validate_renewal_period;
+
+        // Valid renewal periods
+        assert!(validate_renewal_period(1).is_ok());
+        assert!(validate_renewal_period(12).is_ok());
+        assert!(validate_renewal_period(24).is_ok());
+
+        // Invalid renewal periods
+        assert!(validate_renewal_period(0).is_err());
+        assert!(validate_renewal_period(25).is_err());
+        assert!(validate_renewal_period(-1).is_err());
+
+        // Error messages
+        assert_eq!(
+            validate_renewal_period(36).unwrap_err(),
+            "Renewal period must be between 1 and 24 months"
+        );
+    }
+
+    #[test]
+    fn test_subscription_renewal_periods() {
+        use crate::db::subscriptions::{amount_due_for_period, period_bounds, period_number_at};
+        use chrono::{TimeZone, Utc};
+
+        let start = Utc.with_ymd_and_hms(2024, 1, 31, 10, 0, 0).unwrap();
+
+        // Monthly subscription
+        assert_eq!(period_number_at(start, 1, start), 0);
+        assert_eq!(
+            period_number_at(
+                start,
+                1,
+                Utc.with_ymd_and_hms(2024, 2, 29, 9, 59, 59).unwrap()
+            ),
+            0
+        );
+        assert_eq!(
+            period_number_at(
+                start,
+                1,
+                Utc.with_ymd_and_hms(2024, 2, 29, 10, 0, 0).unwrap()
+            ),
+            1
+        );
+        assert_eq!(
+            period_number_at(
+                start,
+                1,
+                Utc.with_ymd_and_hms(2024, 12, 31, 10, 0, 0).unwrap()
+            ),
+            11
+        );
+
+        // Two year subscription
+        assert_eq!(
+            period_number_at(
+                start,
+                24,
+                Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap()
+            ),
+            0
+        );
+        assert_eq!(
+            period_bounds(start, 24, 1),
+            Some((
+                Utc.with_ymd_and_hms(2026, 1, 31, 10, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2028, 1, 31, 10, 0, 0).unwrap()
+            ))
+        );
+
+        // Discount only applies to the first period
+        assert_eq!(
+            amount_due_for_period(&bd("500.00"), &bd("0.10"), 0),
+            bd("450.00")
+        );
+        assert_eq!(
+            amount_due_for_period(&bd("500.00"), &bd("0.10"), 1),
+            bd("500.00")
+        );
+        assert_eq!(
+            amount_due_for_period(&bd("99.99"), &bd("0.15"), 0),
+            bd("84.99")
+        );
+        assert_eq!(
+            amount_due_for_period(&bd("99.99"), &bd("0"), 0),
+            bd("99.99")
+        );
+    }
+
+    #[test]
+    fn test_subscription_proration() {
+        use crate::client::{ClientId, Subscription, SubscriptionStatus};
+        use crate::db::subscriptions::{current_period_number, prorate};
+        use chrono::{TimeZone, Utc};
+
+        // Half of the period left
+        assert_eq!(prorate(&bd("100.00"), 15, 30), bd("50.00"));
+        // Rounded to the grosz
+        assert_eq!(prorate(&bd("100.00"), 1, 3), bd("33.33"));
+        assert_eq!(prorate(&bd("100.00"), 2, 3), bd("66.67"));
+        // Nothing or everything left
+        assert_eq!(prorate(&bd("100.00"), 0, 30), bd("0.00"));
+        assert_eq!(prorate(&bd("100.00"), 30, 30), bd("100.00"));
+        // Out of range values are clamped
+        assert_eq!(prorate(&bd("100.00"), -5, 30), bd("0.00"));
+        assert_eq!(prorate(&bd("100.00"), 45, 30), bd("100.00"));
+        assert_eq!(prorate(&bd("100.00"), 10, 0), bd("0"));
+
+        // After a plan change in period 2 the new monthly periods start at the billing anchor
+        let subscription = Subscription {
+            id: 1,
+            name: "Pro".to_string(),
+            product_id: 1,
+            client_id: ClientId::Company("1234567890".to_string()),
+            renewal_period_months: 1,
+            price: bd("100.00"),
+            start_date: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            billing_anchor: Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap(),
+            period_offset: 3,
+            status: SubscriptionStatus::Active,
+            cancelled_at: None,
+        };
+        assert_eq!(
+            current_period_number(
+                &subscription,
+                Utc.with_ymd_and_hms(2024, 3, 20, 0, 0, 0).unwrap()
+            ),
+            2
+        );
+        assert_eq!(
+            current_period_number(
+                &subscription,
+                Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap()
+            ),
+            3
+        );
+        assert_eq!(
+            current_period_number(
+                &subscription,
+                Utc.with_ymd_and_hms(2024, 6, 15, 0, 0, 0).unwrap()
+            ),
+            5
+        );
+    }
+
+    #[test]
+    fn test_contract_signing_window() {
+        use crate::db::contracts::validate_signing_window;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let error_code = |days: i64, hours: i64| {
+            validate_signing_window(
+                &start,
+                &(start + Duration::days(days) + Duration::hours(hours)),
+            )
+            .err()
+            .map(|e| e.code)
+        };
+
+        // Boundaries are inclusive
+        assert_eq!(error_code(3, 0), None);
+        assert_eq!(error_code(14, 0), None);
+        assert_eq!(error_code(30, 0), None);
+
+        assert_eq!(error_code(2, 23), Some("signing_window_too_short"));
+        assert_eq!(error_code(30, 1), Some("signing_window_too_long"));
+        assert_eq!(error_code(0, 0), Some("end_date_not_after_start_date"));
+        assert_eq!(error_code(-5, 0), Some("end_date_not_after_start_date"));
+    }
+
+    #[test]
+    fn test_contract_pricing() {
+        use crate::db::pricing::{calculate_price, validate_years_supported};
+
+        // Support surcharge is added before discounts
+        let price = calculate_price(&bd("5000.00"), 2, &bd("0.10"), &bd("0.05"));
+        assert_eq!(price.support_price, bd("2000"));
+        assert_eq!(price.list_price, bd("7000.00"));
+        assert_eq!(price.product_discount, bd("700.00"));
+        assert_eq!(price.returning_customer_discount, bd("350.00"));
+        assert_eq!(price.final_price, bd("5950.00"));
+
+        // No discounts
+        let price = calculate_price(&bd("999.99"), 1, &bd("0"), &bd("0"));
+        assert_eq!(price.final_price, bd("1999.99"));
+
+        // Discount amounts are rounded to the grosz
+        let price = calculate_price(&bd("333.33"), 1, &bd("0.125"), &bd("0"));
+        assert_eq!(price.product_discount, bd("166.67"));
+        assert_eq!(price.final_price, bd("1166.66"));
+
+        // Default range is 1 to 3 years
+        assert!(validate_years_supported(0, 1, 3).is_err());
+        assert!(validate_years_supported(1, 1, 3).is_ok());
+        assert!(validate_years_supported(3, 1, 3).is_ok());
+        assert!(validate_years_supported(4, 1, 3).is_err());
+        // Products can allow other ranges
+        assert!(validate_years_supported(0, 0, 1).is_ok());
+        assert!(validate_years_supported(2, 0, 1).is_err());
+        assert_eq!(
+            validate_years_supported(5, 1, 3).unwrap_err().code,
+            "years_supported_out_of_range"
+        );
+    }
+
+    #[test]
+    fn test_contract_status_transitions() {
+        use crate::client::ContractStatus::{self, *};
+        use crate::db::contracts::is_allowed_transition;
+
+        // Happy path through the lifecycle
+        assert!(is_allowed_transition(Draft, AwaitingPayment));
+        assert!(is_allowed_transition(AwaitingPayment, Signed));
+        assert!(is_allowed_transition(Signed, Active));
+        // Single payment signs a draft straight away
+        assert!(is_allowed_transition(Draft, Signed));
+
+        // Unpaid contracts expire, paid ones are refunded instead
+        assert!(is_allowed_transition(Draft, Expired));
+        assert!(is_allowed_transition(AwaitingPayment, Expired));
+        assert!(!is_allowed_transition(Active, Expired));
+        assert!(is_allowed_transition(Active, Refunded));
+        assert!(!is_allowed_transition(AwaitingPayment, Refunded));
+
+        // A partial refund puts a paid contract back to awaiting payment
+        assert!(is_allowed_transition(Signed, AwaitingPayment));
+        assert!(is_allowed_transition(Active, AwaitingPayment));
+
+        // No going back otherwise
+        assert!(!is_allowed_transition(Active, Signed));
+        assert!(!is_allowed_transition(AwaitingPayment, Draft));
+        assert!(!is_allowed_transition(Draft, Active));
+
+        // Final statuses stay final
+        let all = [
+            Draft,
+            AwaitingPayment,
+            Signed,
+            Active,
+            Expired,
+            Cancelled,
+            Refunded,
+        ];
+        for from in [Expired, Cancelled, Refunded] {
+            for to in all {
+                assert!(!is_allowed_transition(from, to));
+            }
+        }
+        // A status never transitions to itself
+        for status in all {
+            assert!(!is_allowed_transition(status, status));
+            assert_eq!(ContractStatus::from_db(status.as_str()), Some(status));
+        }
+    }
+
+    #[test]
+    fn test_contract_balance() {
+        use crate::client::{ClientId, Contract, ContractStatus, Payment};
+        use crate::db::contracts::balance_from_payments;
+        use chrono::{TimeZone, Utc};
+
+        let date = Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        let contract = |status| Contract {
+            id: 1,
+            price: bd("3000.00"),
+            product_id: 1,
+            client_id: ClientId::Individual("12345678901".to_string()),
+            start_date: date,
+            end_date: date,
+            years_supported: 2,
+            status,
+            renewed_from_id: None,
+        };
+        let payment = |amount: &str, is_deleted| Payment {
+            id: 1,
+            contract_id: 1,
+            amount: bd(amount),
+            payment_date: date,
+            is_deleted,
+            reason: None,
+            refunded_payment_id: None,
+        };
+
+        // Nothing paid yet
+        let balance = balance_from_payments(&contract(ContractStatus::Draft), &[]);
+        assert_eq!(balance.price, bd("3000.00"));
+        assert_eq!(balance.total_paid, bd("0"));
+        assert_eq!(balance.total_refunded, bd("0"));
+        assert_eq!(balance.remaining_due, bd("3000.00"));
+        // Deleted payments don't count
+        let payments = [payment("1000.00", false), payment("500.00", true)];
+        let balance = balance_from_payments(&contract(ContractStatus::AwaitingPayment), &payments);
+        assert_eq!(balance.total_paid, bd("1000.00"));
+        assert_eq!(balance.remaining_due, bd("2000.00"));
+        // Refunds are reported separately and raise what is due again
+        let payments = [payment("1000.00", false), payment("-400.00", false)];
+        let balance = balance_from_payments(&contract(ContractStatus::AwaitingPayment), &payments);
+        assert_eq!(balance.total_paid, bd("1000.00"));
+        assert_eq!(balance.total_refunded, bd("400.00"));
+        assert_eq!(balance.net_paid(), bd("600.00"));
+        assert_eq!(balance.remaining_due, bd("2400.00"));
+        // Never negative
+        let balance = balance_from_payments(
+            &contract(ContractStatus::AwaitingPayment),
+            &[payment("3500.00", false)],
+        );
+        assert_eq!(balance.remaining_due, bd("0"));
+        // Paid or closed contracts have nothing left to pay
+        for status in [
+            ContractStatus::Active,
+            ContractStatus::Expired,
+            ContractStatus::Cancelled,
+        ] {
+            let balance = balance_from_payments(&contract(status), &[payment("1000.00", false)]);
+            assert_eq!(balance.total_paid, bd("1000.00"));
+            assert_eq!(balance.remaining_due, bd("0"));
+        }
+    }
+
+    #[test]
+    fn test_contract_expiry_interval() {
+        use crate::worker::{parse_expiry_interval, DEFAULT_EXPIRY_INTERVAL_SECS};
+        use std::time::Duration;
+
+        assert_eq!(
+            parse_expiry_interval(None),
+            Ok(Duration::from_secs(DEFAULT_EXPIRY_INTERVAL_SECS))
+        );
+        assert_eq!(
+            parse_expiry_interval(Some("60")),
+            Ok(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_expiry_interval(Some(" 5 ")),
+            Ok(Duration::from_secs(5))
+        );
+
+        assert!(parse_expiry_interval(Some("0")).is_err());
+        assert!(parse_expiry_interval(Some("-1")).is_err());
+        assert!(parse_expiry_interval(Some("hourly")).is_err());
+    }
+
+    #[test]
+    fn test_installment_plan_validation() {
+        use crate::client::InstallmentPlanEntry;
+        use crate::db::installments::validate_plan;
+        use chrono::{Duration, TimeZone, Utc};
+
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        let end = start + Duration::days(30);
+        let entry = |amount: &str, days| InstallmentPlanEntry {
+            amount: bd(amount),
+            due_date: start + Duration::days(days),
+        };
+        let code = |plan: &[InstallmentPlanEntry]| {
+            validate_plan(plan, &bd("3000.00"), &start, &end)
+                .err()
+                .map(|e| e.code)
+        };
+
+        assert_eq!(code(&[entry("1000", 5), entry("2000", 20)]), None);
+        assert_eq!(code(&[entry("3000.00", 0)]), None);
+        assert_eq!(code(&[]), Some("installment_plan_empty"));
+        assert_eq!(
+            code(&[entry("3500", 5), entry("-500", 20)]),
+            Some("installment_amount_not_positive")
+        );
+        // Due dates have to fall within the contract's signing window
+        assert_eq!(
+            code(&[entry("1000", -1), entry("2000", 20)]),
+            Some("installment_due_date_out_of_window")
+        );
+        assert_eq!(
+            code(&[entry("1000", 5), entry("2000", 31)]),
+            Some("installment_due_date_out_of_window")
+        );
+        // and come one after another
+        assert_eq!(
+            code(&[entry("1000", 20), entry("2000", 5)]),
+            Some("installment_due_dates_not_ascending")
+        );
+        assert_eq!(
+            code(&[entry("1000", 5), entry("2000", 5)]),
+            Some("installment_due_dates_not_ascending")
+        );
+        assert_eq!(
+            code(&[entry("1000", 5), entry("1999.99", 20)]),
+            Some("installment_plan_total_mismatch")
+        );
+        // Adds up, but would be rounded when stored
+        assert_eq!(
+            code(&[entry("1000.005", 5), entry("1999.995", 20)]),
+            Some("installment_amount_too_precise")
+        );
+        assert_eq!(code(&[entry("1000.000", 5), entry("2000.0", 20)]), None);
+    }
+
+    // Creates a migrated scratch database for a test that needs Postgres. The server comes
+    // from DATABASE_URL or .env and has to be reachable, these tests fail rather than pass
+    // without having run.
+    async fn scratch_database(name: &str) -> (sqlx::PgPool, sqlx::PgPool, String) {
+        use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+        dotenvy::dotenv().ok();
+        let url = std::env::var("DATABASE_URL")
+            .expect("DATABASE_URL has to point at a Postgres server for the database tests");
+        let options = PgConnectOptions::from_str(&url).unwrap();
+
+        let admin = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await
+            .unwrap_or_else(|e| panic!("Database for the {} test is not reachable: {}", name, e));
+
+        let database = format!("untergang_{}_{}", name, std::process::id());
+        sqlx::query(&format!(
+            "DROP DATABASE IF EXISTS \"{}\" WITH (FORCE)",
+            database
+        ))
+        .execute(&admin)
+        .await
+        .unwrap();
+        sqlx::query(&format!("CREATE DATABASE \"{}\"", database))
+            .execute(&admin)
+            .await
+            .unwrap();
+
+        let pool = PgPoolOptions::new()
+            .max_connections(10)
+            .connect_with(options.database(&database))
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        (admin, pool, database)
+    }
+
+    async fn drop_scratch_database(admin: sqlx::PgPool, pool: sqlx::PgPool, database: String) {
+        pool.close().await;
+        sqlx::query(&format!(
+            "DROP DATABASE IF EXISTS \"{}\" WITH (FORCE)",
+            database
+        ))
+        .execute(&admin)
+        .await
+        .unwrap();
+    }
+
+    // Seeds a company client, a product for 1 000 zł and a contract for it with one year of
+    // support (2 000 zł) open for the next 10 days, returns the client and the contract id
+    async fn seed_contract(pool: &sqlx::PgPool) -> (crate::client::ClientId, i32) {
+        use chrono::{Duration, Utc};
+
+        let now = Utc::now();
+        seed_contract_dated(pool, now, now + Duration::days(10)).await
+    }
+
+    // Same as seed_contract with the given signing window
+    async fn seed_contract_dated(
+        pool: &sqlx::PgPool,
+        start_date: chrono::DateTime<chrono::Utc>,
+        end_date: chrono::DateTime<chrono::Utc>,
+    ) -> (crate::client::ClientId, i32) {
+        use crate::client::ClientId;
+
+        sqlx::query(
+            "INSERT INTO company_client (name, address, email, phone_number, krs)
+             VALUES ('Acme', 'Warszawa', 'acme@example.com', '123456789', '1234567890')",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        let product_id = sqlx::query_scalar::<_, i32>(
+            "INSERT INTO software (name, description, version, category, price)
+             VALUES ('Office', 'Office suite', '1.0', 'office', 1000) RETURNING id",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        let client_id = ClientId::Company("1234567890".to_string());
+        let contract_id = add_contract(pool, &client_id, product_id, start_date, end_date).await;
+
+        (client_id, contract_id)
+    }
+
+    // Another 2 000 zł contract for a client and product that are already seeded
+    async fn add_contract(
+        pool: &sqlx::PgPool,
+        client_id: &crate::client::ClientId,
+        product_id: i32,
+        start_date: chrono::DateTime<chrono::Utc>,
+        end_date: chrono::DateTime<chrono::Utc>,
+    ) -> i32 {
+        use crate::db::{create_contract_in_db, pricing};
+
+        let price = pricing::calculate_price(&bd("1000.00"), 1, &bd("0"), &bd("0"));
+        let mut tx = pool.begin().await.unwrap();
+        let contract_id = create_contract_in_db(
+            &mut tx,
+            &price,
+            &product_id,
+            client_id,
+            &start_date,
+            &end_date,
+            None,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        contract_id
+    }
+
+    // Monthly 1 000 zł subscription of a new company client that started ten days ago
+    async fn seed_subscription(pool: &sqlx::PgPool) -> (crate::client::ClientId, i32) {
+        use crate::client::ClientId;
+        use crate::db::subscriptions;
+
+        sqlx::query(
+            "INSERT INTO company_client (name, address, email, phone_number, krs)
+             VALUES ('Acme', 'Warszawa', 'acme@example.com', '123456789', '1234567890')",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        let product_id = sqlx::query_scalar::<_, i32>(
+            "INSERT INTO software (name, description, version, category, price)
+             VALUES ('Office', 'Office suite', '1.0', 'office', 1000) RETURNING id",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        let client_id = ClientId::Company("1234567890".to_string());
+        let subscription = subscriptions::create_subscription(
+            pool,
+            "Office monthly",
+            &client_id,
+            product_id,
+            1,
+            &bd("1000.00"),
+        )
+        .await
+        .unwrap();
+        sqlx::query(
+            "UPDATE subscription
+             SET start_date = start_date - INTERVAL '10 days', billing_anchor = billing_anchor - INTERVAL '10 days'
+             WHERE id = $1",
+        )
+        .bind(subscription.id)
+        .execute(pool)
+        .await
+        .unwrap();
+
+        (client_id, subscription.id)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_payments_for_one_contract() {
+        use crate::handler::{create_payment, PaymentRequest};
+        use axum::{extract::State, Json};
+
+        let (admin, pool, database) = scratch_database("concurrent_payments").await;
+        let (client_id, contract_id) = seed_contract(&pool).await;
+
+        // Several clerks submit the full payment for the same contract at once
+        let attempts = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                let request: PaymentRequest = serde_json::from_value(serde_json::json!({
+                    "SinglePayment": {
+                        "contract_id": contract_id,
+                        "client_id": client_id,
+                        "amount": "2000.00",
+                    }
+                }))
+                .unwrap();
+                tokio::spawn(async move { create_payment(State(pool), Json(request)).await })
+            })
+            .collect::<Vec<_>>();
+        let mut accepted = 0;
+        for attempt in attempts {
+            if attempt.await.unwrap().is_ok() {
+                accepted += 1;
+            }
+        }
+
+        let paid = sqlx::query_scalar::<_, BigDecimal>(
+            "SELECT COALESCE(SUM(amount), 0) FROM payment WHERE contract_id = $1",
+        )
+        .bind(contract_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let status = sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1")
+            .bind(contract_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let signed = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM contract_status_history WHERE contract_id = $1 AND to_status = 'signed'",
+        )
+        .bind(contract_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        // Exactly one payment goes through, the contract is paid once and signed once
+        assert_eq!(accepted, 1);
+        assert_eq!(paid, bd("2000.00"));
+        assert_eq!(status, "active");
+        assert_eq!(signed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_installments_pay_off_remaining_due() {
+        use crate::db::{contracts, payments};
+
+        let (admin, pool, database) = scratch_database("installments").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+
+        // The first installment goes through and leaves the rest due
+        let first = payments::pay_installment(&pool, contract_id, &bd("500.00")).await;
+        let mut conn = pool.acquire().await.unwrap();
+        let after_first = contracts::get_balance(&mut conn, contract_id)
+            .await
+            .unwrap();
+        drop(conn);
+        // Paying more than what is left is rejected
+        let overpaid = payments::pay_installment(&pool, contract_id, &bd("1600.00")).await;
+        // The last installment covers exactly what is left
+        let last = payments::pay_installment(&pool, contract_id, &bd("1500.00")).await;
+        let mut conn = pool.acquire().await.unwrap();
+        let after_last = contracts::get_balance(&mut conn, contract_id)
+            .await
+            .unwrap();
+        drop(conn);
+        let status = sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1")
+            .bind(contract_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert!(first.is_ok());
+        assert_eq!(after_first.total_paid, bd("500.00"));
+        assert_eq!(after_first.remaining_due, bd("1500.00"));
+        assert!(overpaid.is_err());
+        assert!(last.is_ok());
+        assert_eq!(after_last.total_paid, bd("2000.00"));
+        assert_eq!(after_last.remaining_due, bd("0"));
+        assert_eq!(status, "active");
+    }
+
+    #[tokio::test]
+    async fn test_partial_refund_reopens_paid_contract() {
+        use crate::client::ContractStatus;
+        use crate::db::payments;
+
+        let (admin, pool, database) = scratch_database("partial_refund").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        let payment_id =
+            sqlx::query_scalar::<_, i32>("SELECT id FROM payment WHERE contract_id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        // Paid a few months ago, so part of the revenue has been recognized already
+        sqlx::query(
+            "UPDATE revenue_schedule SET recognition_date = recognition_date - INTERVAL '3 months' WHERE contract_id = $1",
+        )
+        .bind(contract_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let schedule = |pool: sqlx::PgPool| async move {
+            sqlx::query_as::<_, (i64, i64, BigDecimal)>(
+                "SELECT COUNT(*),
+                        COUNT(*) FILTER (WHERE recognition_date > (NOW() AT TIME ZONE 'UTC')),
+                        COALESCE(SUM(amount), 0)
+                 FROM revenue_schedule WHERE contract_id = $1 AND is_deleted = FALSE",
+            )
+            .bind(contract_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+        };
+        let (scheduled, future, _) = schedule(pool.clone()).await;
+
+        let partial =
+            payments::refund_payment(&pool, payment_id, Some(&bd("500.00")), "Damaged media").await;
+        // Only 1500.00 of the payment is left to give back
+        let too_much =
+            payments::refund_payment(&pool, payment_id, Some(&bd("1500.01")), "Oops").await;
+        let after_refund = schedule(pool.clone()).await;
+        // Paying the rest again resumes the schedule
+        let paid_again = payments::pay_installment(&pool, contract_id, &bd("500.00")).await;
+        let after_paying_again = schedule(pool.clone()).await;
+
+        drop_scratch_database(admin, pool, database).await;
+
+        let partial = partial.unwrap();
+        assert_eq!(partial.refund.amount, bd("-500.00"));
+        assert_eq!(partial.refund.refunded_payment_id, Some(payment_id));
+        assert_eq!(partial.contract_status, ContractStatus::AwaitingPayment);
+        assert_eq!(partial.balance.total_refunded, bd("500.00"));
+        assert_eq!(partial.balance.remaining_due, bd("500.00"));
+        assert!(too_much.is_err());
+        // Revenue recognized before the refund stays, nothing more is recognized
+        assert!(future > 0 && future < scheduled);
+        assert_eq!(after_refund.0, scheduled - future);
+        assert_eq!(after_refund.1, 0);
+        assert!(paid_again.is_ok());
+        assert_eq!(after_paying_again.0, scheduled);
+        assert_eq!(after_paying_again.2, bd("2000.00"));
+    }
+
+    #[tokio::test]
+    async fn test_partial_refund_after_end_date_does_not_expire() {
+        use crate::db::payments;
+        use crate::handler::{create_payment, PaymentRequest};
+        use crate::worker::expire_unpaid_contracts;
+        use axum::extract::{Json, State};
+
+        let (admin, pool, database) = scratch_database("partial_refund_after_end_date").await;
+        let (client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        let payment_id =
+            sqlx::query_scalar::<_, i32>("SELECT id FROM payment WHERE contract_id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        // Paid within the signing window, which has closed since
+        sqlx::query(
+            "UPDATE contract SET start_date = start_date - INTERVAL '20 days', end_date = end_date - INTERVAL '20 days' WHERE id = $1",
+        )
+        .bind(contract_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        payments::refund_payment(&pool, payment_id, Some(&bd("100.00")), "Goodwill")
+            .await
+            .unwrap();
+
+        expire_unpaid_contracts(&pool).await;
+        let status_after_run =
+            sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let payment: PaymentRequest = serde_json::from_value(serde_json::json!({
+            "SinglePayment": {
+                "contract_id": contract_id,
+                "amount": "100.00",
+                "client_id": client_id,
+            }
+        }))
+        .unwrap();
+        let paid_off = create_payment(State(pool.clone()), Json(payment)).await;
+        let status = sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1")
+            .bind(contract_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let amounts = sqlx::query_scalar::<_, BigDecimal>(
+            "SELECT amount FROM payment WHERE contract_id = $1 ORDER BY id",
+        )
+        .bind(contract_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        // The worker leaves the signed contract alone, the client can pay off what is owed
+        assert_eq!(status_after_run, "awaiting_payment");
+        assert!(paid_off.is_ok());
+        assert_eq!(status, "active");
+        assert_eq!(amounts, vec![bd("2000.00"), bd("-100.00"), bd("100.00")]);
+    }
+
+    #[tokio::test]
+    async fn test_refund_reopens_settled_installment() {
+        use crate::client::InstallmentPlanEntry;
+        use crate::db::{installments, payments};
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("refund_installment").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        let now = Utc::now();
+        let plan = [
+            InstallmentPlanEntry {
+                amount: bd("1000.00"),
+                due_date: now + Duration::days(1),
+            },
+            InstallmentPlanEntry {
+                amount: bd("1000.00"),
+                due_date: now + Duration::days(2),
+            },
+        ];
+        let mut conn = pool.acquire().await.unwrap();
+        installments::create_plan(&mut conn, contract_id, &plan)
+            .await
+            .unwrap();
+        drop(conn);
+        payments::pay_installment(&pool, contract_id, &bd("1000.00"))
+            .await
+            .unwrap();
+        payments::pay_installment(&pool, contract_id, &bd("1000.00"))
+            .await
+            .unwrap();
+        let second_payment_id =
+            sqlx::query_scalar::<_, i32>("SELECT MAX(id) FROM payment WHERE contract_id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        let refund =
+            payments::refund_payment(&pool, second_payment_id, Some(&bd("400.00")), "Discount")
+                .await;
+        let overdue = installments::list_overdue(&pool, now + Duration::days(3))
+            .await
+            .unwrap();
+        // The reopened installment is worth more than is left, only the rest is due
+        let full_installment = payments::pay_installment(&pool, contract_id, &bd("1000.00")).await;
+        let rest = payments::pay_installment(&pool, contract_id, &bd("400.00")).await;
+        let status = sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1")
+            .bind(contract_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert!(refund.is_ok());
+        assert_eq!(overdue.len(), 1);
+        assert_eq!(overdue[0].installment_number, 2);
+        assert_eq!(overdue[0].amount, bd("400.00"));
+        assert!(full_installment.is_err());
+        assert!(rest.is_ok());
+        assert_eq!(status, "active");
+    }
+
+    #[tokio::test]
+    async fn test_full_refund_of_paid_contract() {
+        use crate::client::ContractStatus;
+        use crate::db::payments;
+
+        let (admin, pool, database) = scratch_database("full_refund").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        let payment_id =
+            sqlx::query_scalar::<_, i32>("SELECT id FROM payment WHERE contract_id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        let refund = payments::refund_payment(&pool, payment_id, None, "Wrong product").await;
+        let again = payments::refund_payment(&pool, payment_id, None, "Wrong product").await;
+
+        drop_scratch_database(admin, pool, database).await;
+
+        let refund = refund.unwrap();
+        assert_eq!(refund.refund.amount, bd("-2000.00"));
+        assert_eq!(refund.contract_status, ContractStatus::Refunded);
+        assert_eq!(refund.balance.remaining_due, bd("0"));
+        assert!(again.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_installment_plan_payments_and_overdue() {
+        use crate::client::InstallmentPlanEntry;
+        use crate::db::{installments, payments};
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("installment_plan").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        let now = Utc::now();
+        let plan = [
+            InstallmentPlanEntry {
+                amount: bd("800.00"),
+                due_date: now + Duration::days(1),
+            },
+            InstallmentPlanEntry {
+                amount: bd("1200.00"),
+                due_date: now + Duration::days(5),
+            },
+        ];
+        let mut conn = pool.acquire().await.unwrap();
+        installments::create_plan(&mut conn, contract_id, &plan)
+            .await
+            .unwrap();
+        drop(conn);
+
+        // Only the next installment's amount is accepted
+        let wrong_amount = payments::pay_installment(&pool, contract_id, &bd("1200.00")).await;
+        let first = payments::pay_installment(&pool, contract_id, &bd("800.00")).await;
+        let overdue_later = installments::list_overdue(&pool, now + Duration::days(6))
+            .await
+            .unwrap();
+        let overdue_now = installments::list_overdue(&pool, now).await.unwrap();
+        let last = payments::pay_installment(&pool, contract_id, &bd("1200.00")).await;
+        let overdue_after_paying = installments::list_overdue(&pool, now + Duration::days(6))
+            .await
+            .unwrap();
+        let schedule = installments::get_installments_for_contract(&pool, contract_id)
+            .await
+            .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert!(wrong_amount.is_err());
+        assert!(first.is_ok());
+        assert_eq!(overdue_later.len(), 1);
+        assert_eq!(overdue_later[0].contract_id, contract_id);
+        assert_eq!(overdue_later[0].installment_number, 2);
+        assert_eq!(overdue_later[0].days_overdue, 1);
+        assert!(overdue_now.is_empty());
+        assert!(last.is_ok());
+        assert!(overdue_after_paying.is_empty());
+        assert!(schedule
+            .iter()
+            .all(|installment| installment.payment_id.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_closed_period_allows_corrections_today() {
+        use crate::db::{contracts, create_contract_in_db, payments, periods, pricing};
+        use crate::worker::expire_unpaid_contracts;
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("closed_period").await;
+        // Both signed up in the previous month, one is still in its signing window
+        let now = Utc::now();
+        let start_date = now - Duration::days(40);
+        let (client_id, open_id) =
+            seed_contract_dated(&pool, start_date, now + Duration::days(5)).await;
+        let product_id =
+            sqlx::query_scalar::<_, i32>("SELECT product_id FROM contract WHERE id = $1")
+                .bind(open_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let lapsed_id = add_contract(
+            &pool,
+            &client_id,
+            product_id,
+            start_date,
+            now - Duration::days(1),
+        )
+        .await;
+
+        let closed = periods::close_period(&pool, periods::period_of(&start_date)).await;
+        let closed_again = periods::close_period(&pool, periods::period_of(&start_date)).await;
+        // Payments, refunds and status changes are booked today, in the open period
+        let payment = payments::pay_installment(&pool, open_id, &bd("500.00")).await;
+        let cancellation = contracts::cancel_contract(&pool, open_id, "Too late").await;
+        expire_unpaid_contracts(&pool).await;
+        // A new contract would be dated in the closed period
+        let price = pricing::calculate_price(&bd("1000.00"), 1, &bd("0"), &bd("0"));
+        let mut tx = pool.begin().await.unwrap();
+        let backdated = create_contract_in_db(
+            &mut tx,
+            &price,
+            &product_id,
+            &client_id,
+            &start_date,
+            &(now + Duration::days(5)),
+            None,
+        )
+        .await;
+        drop(tx);
+        let statuses =
+            sqlx::query_as::<_, (i32, String)>("SELECT id, status FROM contract ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        let amounts = sqlx::query_scalar::<_, BigDecimal>(
+            "SELECT amount FROM payment WHERE contract_id = $1 ORDER BY id",
+        )
+        .bind(open_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert!(closed.unwrap());
+        // Closing twice is reported instead of failing on the primary key
+        assert!(!closed_again.unwrap());
+        assert!(payment.is_ok());
+        assert_eq!(cancellation.unwrap(), bd("500.00"));
+        assert!(backdated.is_err());
+        assert_eq!(
+            statuses,
+            vec![
+                (open_id, "cancelled".to_string()),
+                (lapsed_id, "expired".to_string()),
+            ]
+        );
+        assert_eq!(amounts, vec![bd("500.00"), bd("-500.00")]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_contract_renewal() {
+        use crate::db::contracts;
+        use crate::handler::{renew_contract, RenewContractRequest};
+        use axum::extract::{Json, Path, State};
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("renewal").await;
+        let now = Utc::now();
+        let (client_id, expired_id) =
+            seed_contract_dated(&pool, now - Duration::days(20), now - Duration::days(1)).await;
+        contracts::expire_contract(&pool, expired_id).await.unwrap();
+
+        let request = || -> RenewContractRequest {
+            serde_json::from_value(serde_json::json!({
+                "client_id": client_id,
+                "start_date": now,
+                "end_date": now + Duration::days(10),
+            }))
+            .unwrap()
+        };
+
+        // The same expired contract renewed several times at once
+        let attempts = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                let request = request();
+                tokio::spawn(async move {
+                    renew_contract(State(pool), Path(expired_id), Json(request)).await
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut renewed_ids = Vec::new();
+        for attempt in attempts {
+            if let Ok((_, Json(details))) = attempt.await.unwrap() {
+                renewed_ids.push(details.contract.id);
+            }
+        }
+        let renewed_again =
+            renew_contract(State(pool.clone()), Path(expired_id), Json(request())).await;
+        // The renewal itself is a fresh draft, not an expired contract
+        let renewal_renewed = match renewed_ids.first() {
+            Some(&renewed_id) => {
+                renew_contract(State(pool.clone()), Path(renewed_id), Json(request()))
+                    .await
+                    .is_ok()
+            }
+            None => false,
+        };
+        let renewals = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM contract WHERE renewed_from_id = $1",
+        )
+        .bind(expired_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert_eq!(renewed_ids.len(), 1);
+        assert!(renewed_again.is_err());
+        assert!(!renewal_renewed);
+        assert_eq!(renewals, 1);
+    }
+
+    #[tokio::test]
+    async fn test_contract_cancellation() {
+        use crate::client::ContractStatus;
+        use crate::db::payments;
+        use crate::handler::{cancel_contract, CancelContractRequest};
+        use axum::extract::{Json, Path, State};
+
+        let (admin, pool, database) = scratch_database("cancellation").await;
+        let (client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_installment(&pool, contract_id, &bd("750.00"))
+            .await
+            .unwrap();
+
+        let request = || -> CancelContractRequest {
+            serde_json::from_value(serde_json::json!({
+                "client_id": client_id,
+                "reason": "Bought elsewhere",
+            }))
+            .unwrap()
+        };
+        let cancelled =
+            cancel_contract(State(pool.clone()), Path(contract_id), Json(request())).await;
+        let cancelled_again =
+            cancel_contract(State(pool.clone()), Path(contract_id), Json(request())).await;
+
+        let refunds = sqlx::query_as::<_, (BigDecimal, Option<String>)>(
+            "SELECT amount, reason FROM payment WHERE contract_id = $1 AND amount < 0",
+        )
+        .bind(contract_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let status = sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1")
+            .bind(contract_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let history = sqlx::query_as::<_, (Option<String>, String)>(
+            "SELECT from_status, to_status FROM contract_status_history
+             WHERE contract_id = $1 AND to_status = 'cancelled'",
+        )
+        .bind(contract_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        let Json(summary) = cancelled.unwrap();
+        assert_eq!(summary.status, ContractStatus::Cancelled);
+        assert_eq!(summary.amount_paid, bd("750.00"));
+        assert_eq!(summary.amount_refunded, bd("750.00"));
+        assert_eq!(summary.reason, "Bought elsewhere");
+        // A single compensating payment gives everything back
+        assert_eq!(
+            refunds,
+            vec![(bd("-750.00"), Some("Bought elsewhere".to_string()))]
+        );
+        assert_eq!(status, "cancelled");
+        assert_eq!(
+            history,
+            vec![(
+                Some("awaiting_payment".to_string()),
+                "cancelled".to_string()
+            )]
+        );
+        assert!(cancelled_again.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expire_unpaid_contracts() {
+        use crate::worker::expire_unpaid_contracts;
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("expiry_worker").await;
+        let now = Utc::now();
+        // Partly paid while it was open, the signing window ended yesterday
+        let (client_id, overdue_id) =
+            seed_contract_dated(&pool, now - Duration::days(10), now + Duration::days(5)).await;
+        crate::db::payments::pay_installment(&pool, overdue_id, &bd("600.00"))
+            .await
+            .unwrap();
+        sqlx::query("UPDATE contract SET end_date = $1 WHERE id = $2")
+            .bind((now - Duration::days(1)).naive_utc())
+            .bind(overdue_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let product_id =
+            sqlx::query_scalar::<_, i32>("SELECT product_id FROM contract WHERE id = $1")
+                .bind(overdue_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let open_id =
+            add_contract(&pool, &client_id, product_id, now, now + Duration::days(5)).await;
+
+        expire_unpaid_contracts(&pool).await;
+
+        let status = |contract_id: i32, pool: sqlx::PgPool| async move {
+            sqlx::query_scalar::<_, String>("SELECT status FROM contract WHERE id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+        let overdue_status = status(overdue_id, pool.clone()).await;
+        let open_status = status(open_id, pool.clone()).await;
+        let payments = sqlx::query_as::<_, (i32, BigDecimal)>(
+            "SELECT contract_id, amount FROM payment ORDER BY id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert_eq!(overdue_status, "expired");
+        // Everything that was paid goes back with one compensating payment
+        assert_eq!(
+            payments,
+            vec![(overdue_id, bd("600.00")), (overdue_id, bd("-600.00"))]
+        );
+        assert_eq!(open_status, "draft");
+    }
+
+    #[tokio::test]
+    async fn test_revenue_time_series_keeps_cancelled_contracts() {
+        use crate::client::Granularity;
+        use crate::db::{contracts, payments, revenue};
+        use chrono::{Duration, Months, Utc};
+
+        let (admin, pool, database) = scratch_database("time_series_cancelled").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        // Paid last month, cancelled today
+        let now = Utc::now();
+        let last_month = now.checked_sub_months(Months::new(1)).unwrap();
+        sqlx::query("UPDATE payment SET payment_date = $1 WHERE contract_id = $2")
+            .bind(last_month.naive_utc())
+            .bind(contract_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        contracts::cancel_contract(&pool, contract_id, "Changed our mind")
+            .await
+            .unwrap();
+
+        let series = revenue::calculate_revenue_time_series(
+            &pool,
+            last_month.date_naive(),
+            (now + Duration::days(400)).date_naive(),
+            Granularity::Month,
+        )
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        let cash: Vec<BigDecimal> = series.iter().map(|(_, _, cash)| cash.clone()).collect();
+        let recognized: BigDecimal = series.iter().map(|(_, recognized, _)| recognized).sum();
+        // The payment stays in its month and the refund nets it out in the month it was made
+        assert_eq!(cash, vec![bd("2000.00"), bd("-2000.00")]);
+        // Everything was given back, so the revenue recognized last month is reversed today
+        assert_eq!(recognized, bd("0"));
+    }
+
+    #[tokio::test]
+    async fn test_deferred_revenue_uses_status_as_of_date() {
+        use crate::db::{contracts, payments, revenue};
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("deferred_revenue_as_of").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        // Paid ten days ago, cancelled today
+        let now = Utc::now();
+        let shift =
+            "UPDATE {table} SET {column} = {column} - INTERVAL '10 days' WHERE contract_id = $1";
+        for (table, column) in [
+            ("payment", "payment_date"),
+            ("revenue_schedule", "recognition_date"),
+            ("contract_status_history", "changed_at"),
+        ] {
+            sqlx::query(&shift.replace("{table}", table).replace("{column}", column))
+                .bind(contract_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let last_week = (now - Duration::days(5)).date_naive();
+        let before_cancellation = revenue::calculate_deferred_revenue(&pool, last_week)
+            .await
+            .unwrap();
+        contracts::cancel_contract(&pool, contract_id, "Changed our mind")
+            .await
+            .unwrap();
+        let after_cancellation = revenue::calculate_deferred_revenue(&pool, last_week)
+            .await
+            .unwrap();
+        let today = revenue::calculate_deferred_revenue(&pool, now.date_naive())
+            .await
+            .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert_eq!(before_cancellation.len(), 1);
+        let (id, cash, recognized) = &before_cancellation[0];
+        assert_eq!(*id, contract_id);
+        assert_eq!(*cash, bd("2000.00"));
+        assert!(*recognized > bd("0") && *recognized < bd("2000.00"));
+        // Cancelling today doesn't rewrite a report for a date the contract was still active
+        assert_eq!(after_cancellation, before_cancellation);
+        assert!(today.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_plan_change_charge_is_due() {
+        use crate::handler::{
+            change_subscription_plan, create_subscription_payment, SubscriptionChangeRequest,
+            SubscriptionPaymentRequest,
+        };
+        use axum::extract::{Json, Path, State};
+
+        let (admin, pool, database) = scratch_database("subscription_plan_change").await;
+        let (client_id, subscription_id) = seed_subscription(&pool).await;
+        let premium_id = sqlx::query_scalar::<_, i32>(
+            "INSERT INTO software (name, description, version, category, price)
+             VALUES ('Office Premium', 'Office suite', '2.0', 'office', 3000) RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let pay = |amount: &str| -> SubscriptionPaymentRequest {
+            serde_json::from_value(serde_json::json!({
+                "client_id": client_id,
+                "amount": amount,
+            }))
+            .unwrap()
+        };
+        create_subscription_payment(
+            State(pool.clone()),
+            Path(subscription_id),
+            Json(pay("1000.00")),
+        )
+        .await
+        .unwrap();
+
+        let change: SubscriptionChangeRequest = serde_json::from_value(serde_json::json!({
+            "client_id": client_id,
+            "product_id": premium_id,
+        }))
+        .unwrap();
+        let Json(plan_change) =
+            change_subscription_plan(State(pool.clone()), Path(subscription_id), Json(change))
+                .await
+                .unwrap();
+        let booked_charges = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM payment WHERE subscription_payment_kind = 'proration_charge'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        // The next renewal period has started
+        sqlx::query(
+            "UPDATE subscription SET billing_anchor = CURRENT_TIMESTAMP - INTERVAL '1 day'",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let renewal_only = create_subscription_payment(
+            State(pool.clone()),
+            Path(subscription_id),
+            Json(pay("3000.00")),
+        )
+        .await;
+        let renewal_and_charge = (bd("3000.00") + &plan_change.charged).to_string();
+        let renewal_with_charge = create_subscription_payment(
+            State(pool.clone()),
+            Path(subscription_id),
+            Json(pay(&renewal_and_charge)),
+        )
+        .await;
+        let payments = sqlx::query_as::<_, (i32, String, BigDecimal)>(
+            "SELECT period_number, subscription_payment_kind, amount FROM payment
+             WHERE subscription_id = $1 ORDER BY id",
+        )
+        .bind(subscription_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let outstanding = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM subscription_charge WHERE payment_id IS NULL",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert!(plan_change.charged > bd("0"));
+        // Changing the plan doesn't book money the client hasn't paid
+        assert_eq!(booked_charges, 0);
+        assert!(renewal_only.is_err());
+        assert!(renewal_with_charge.is_ok());
+        assert_eq!(
+            payments,
+            vec![
+                (0, "renewal".to_string(), bd("1000.00")),
+                (0, "proration_credit".to_string(), -plan_change.credited),
+                (1, "renewal".to_string(), bd("3000.00")),
+                (0, "proration_charge".to_string(), plan_change.charged),
+            ]
+        );
+        assert_eq!(outstanding, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_lapsed_subscriptions() {
+        use crate::db::subscriptions;
+        use crate::handler::{create_subscription_payment, SubscriptionPaymentRequest};
+        use crate::worker::cancel_lapsed_subscriptions;
+        use axum::extract::{Json, Path, State};
+
+        let (admin, pool, database) = scratch_database("subscription_lapse").await;
+        let (client_id, lapsed_id) = seed_subscription(&pool).await;
+        let product_id = sqlx::query_scalar::<_, i32>(
+            "INSERT INTO software (name, description, version, category, price)
+             VALUES ('Antivirus', 'Antivirus', '1.0', 'security', 500) RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let paid = subscriptions::create_subscription(
+            &pool,
+            "Antivirus monthly",
+            &client_id,
+            product_id,
+            1,
+            &bd("500.00"),
+        )
+        .await
+        .unwrap();
+        let pay = |amount: &str| -> SubscriptionPaymentRequest {
+            serde_json::from_value(serde_json::json!({
+                "client_id": client_id,
+                "amount": amount,
+            }))
+            .unwrap()
+        };
+        create_subscription_payment(State(pool.clone()), Path(paid.id), Json(pay("500.00")))
+            .await
+            .unwrap();
+        // Both are in their second month, only the second one paid for the first
+        sqlx::query(
+            "UPDATE subscription
+             SET start_date = start_date - INTERVAL '1 month', billing_anchor = billing_anchor - INTERVAL '1 month'",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let late_payment =
+            create_subscription_payment(State(pool.clone()), Path(lapsed_id), Json(pay("1000.00")))
+                .await;
+        let status_before_run =
+            sqlx::query_scalar::<_, String>("SELECT status FROM subscription WHERE id = $1")
+                .bind(lapsed_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        cancel_lapsed_subscriptions(&pool).await;
+        let statuses =
+            sqlx::query_as::<_, (i32, String)>("SELECT id, status FROM subscription ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        // Paying no longer cancels the subscription, the worker does
+        assert!(late_payment.is_err());
+        assert_eq!(status_before_run, "active");
+        assert_eq!(
+            statuses,
+            vec![
+                (lapsed_id, "cancelled".to_string()),
+                (paid.id, "active".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revenue_by_client_segment() {
+        use crate::client::{ClientId, ClientKind};
+        use crate::db::payments;
+        use crate::exchange::{JsonFileRateProvider, SharedRateProvider};
+        use crate::handler::{get_predicted_revenue, RevenueQuery};
+        use axum::extract::{Extension, Query, State};
+        use chrono::{Duration, Utc};
+        use std::sync::Arc;
+
+        let (admin, pool, database) = scratch_database("revenue_by_segment").await;
+        let (_client_id, company_contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, company_contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO personal_client (first_name, last_name, email, phone_number, pesel)
+             VALUES ('Jan', 'Kowalski', 'jan@example.com', '987654321', '90010112345')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let product_id =
+            sqlx::query_scalar::<_, i32>("SELECT product_id FROM contract WHERE id = $1")
+                .bind(company_contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let now = Utc::now();
+        add_contract(
+            &pool,
+            &ClientId::Individual("90010112345".to_string()),
+            product_id,
+            now,
+            now + Duration::days(10),
+        )
+        .await;
+
+        let rate_provider: SharedRateProvider =
+            Arc::new(JsonFileRateProvider::from_json(r#"{"base": "PLN", "rates": {}}"#).unwrap());
+        let report = |query: &str| {
+            let query = Query::<RevenueQuery>::try_from_uri(
+                &format!("http://localhost/revenue?{}", query)
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap();
+            get_predicted_revenue(State(pool.clone()), Extension(rate_provider.clone()), query)
+        };
+        let segments = |report: &crate::client::PredictedRevenueReport| -> Vec<(ClientKind, BigDecimal, BigDecimal)> {
+            report
+                .by_segment
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|segment| (segment.segment, segment.recognized.clone(), segment.pending.clone()))
+                .collect()
+        };
+        let all_clients = report("group_by=segment").await.unwrap().0;
+        let one_company = report("group_by=segment&client_type=company&client_id=1234567890")
+            .await
+            .unwrap()
+            .0;
+        let missing_id = report("group_by=segment&client_type=company").await;
+        let unknown_type = Query::<RevenueQuery>::try_from_uri(
+            &"http://localhost/revenue?client_type=business&client_id=1234567890"
+                .parse()
+                .unwrap(),
+        );
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert_eq!(
+            segments(&all_clients),
+            vec![
+                (ClientKind::Individual, bd("0"), bd("2000.00")),
+                (ClientKind::Company, bd("1000.00"), bd("1000.00")),
+            ]
+        );
+        assert_eq!(
+            segments(&one_company),
+            vec![
+                (ClientKind::Individual, bd("0"), bd("0")),
+                (ClientKind::Company, bd("1000.00"), bd("1000.00")),
+            ]
+        );
+        assert!(missing_id.is_err());
+        assert!(unknown_type.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quote_matches_created_contract_price() {
+        use crate::handler::{create_contract, quote_contract, PurchaseRequest};
+        use axum::extract::{Json, State};
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("quote_matches_contract").await;
+        let now = Utc::now();
+        // The running contract makes the client a returning customer
+        let (client_id, _contract_id) =
+            seed_contract_dated(&pool, now - Duration::days(1), now + Duration::days(10)).await;
+        let product_id = sqlx::query_scalar::<_, i32>(
+            "INSERT INTO software (name, description, version, category, price)
+             VALUES ('Antivirus', 'Antivirus', '1.0', 'security', 1234.57) RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO discount (name, discounted_products, percentage, start_date, end_date)
+             VALUES ('Spring sale', $1, 0.12345, CURRENT_DATE - 1, CURRENT_DATE + 30)",
+        )
+        .bind(product_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let request = || -> PurchaseRequest {
+            serde_json::from_value(serde_json::json!({
+                "client_id": client_id,
+                "start_date": now,
+                "end_date": now + Duration::days(10),
+                "product_id": product_id,
+                "years_supported": 2,
+            }))
+            .unwrap()
+        };
+        let Json(quote) = quote_contract(State(pool.clone()), Json(request()))
+            .await
+            .unwrap();
+        create_contract(State(pool.clone()), Json(request()))
+            .await
+            .unwrap();
+        let contract = sqlx::query_as::<_, (BigDecimal, BigDecimal, BigDecimal)>(
+            "SELECT price, product_discount, returning_customer_discount FROM contract
+             WHERE product_id = $1",
+        )
+        .bind(product_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert!(quote.product_discount > bd("0"));
+        assert_eq!(quote.returning_customer_discount_rate, bd("0.05"));
+        assert_eq!(
+            contract,
+            (
+                quote.final_price,
+                quote.product_discount,
+                quote.returning_customer_discount
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revenue_by_obligation_follows_schedule() {
+        use crate::client::RevenueFilter;
+        use crate::db::{payments, revenue};
+
+        let (admin, pool, database) = scratch_database("revenue_by_obligation").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        // Paid a little over two months ago
+        sqlx::query(
+            "UPDATE revenue_schedule SET recognition_date = recognition_date - INTERVAL '2 months 1 day' WHERE contract_id = $1",
+        )
+        .bind(contract_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let by_obligation =
+            revenue::calculate_revenue_by_obligation(&pool, &RevenueFilter::default())
+                .await
+                .unwrap();
+
+        drop_scratch_database(admin, pool, database).await;
+
+        // The license is recognized on payment, the year of support one month at a time
+        assert_eq!(
+            by_obligation,
+            vec![
+                ("license".to_string(), bd("1000.00"), bd("0")),
+                ("support".to_string(), bd("166.66"), bd("833.34")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_refund_reverses_recognized_revenue() {
+        use crate::client::RevenueFilter;
+        use crate::db::{payments, revenue};
+
+        let (admin, pool, database) = scratch_database("full_refund_revenue").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        let payment_id =
+            sqlx::query_scalar::<_, i32>("SELECT id FROM payment WHERE contract_id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        // Paid a little over two months ago
+        sqlx::query(
+            "UPDATE revenue_schedule SET recognition_date = recognition_date - INTERVAL '2 months 1 day' WHERE contract_id = $1",
+        )
+        .bind(contract_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let filter = RevenueFilter::default();
+        let paid = (
+            revenue::calculate_current_revenue(&pool, &filter)
+                .await
+                .unwrap(),
+            revenue::calculate_pending_revenue(&pool, &filter)
+                .await
+                .unwrap(),
+        );
+        payments::refund_payment(&pool, payment_id, None, "Returned")
+            .await
+            .unwrap();
+        let refunded = (
+            revenue::calculate_current_revenue(&pool, &filter)
+                .await
+                .unwrap(),
+            revenue::calculate_pending_revenue(&pool, &filter)
+                .await
+                .unwrap(),
+        );
+
+        drop_scratch_database(admin, pool, database).await;
+
+        // Current revenue is what the schedule recognized so far, the rest is still pending
+        assert_eq!(paid, (bd("1166.66"), bd("833.34")));
+        assert_eq!(refunded, (bd("0"), bd("0")));
+    }
+
+    #[tokio::test]
+    async fn test_partial_refund_of_payment_in_full_reopens_what_it_covers() {
+        use crate::client::InstallmentPlanEntry;
+        use crate::db::{installments, payments};
+        use chrono::{Duration, Utc};
+
+        let (admin, pool, database) = scratch_database("refund_payment_in_full").await;
+        let (_client_id, contract_id) = seed_contract(&pool).await;
+        let now = Utc::now();
+        let plan: Vec<InstallmentPlanEntry> = (1..=4)
+            .map(|day| InstallmentPlanEntry {
+                amount: bd("500.00"),
+                due_date: now + Duration::days(day),
+            })
+            .collect();
+        let mut conn = pool.acquire().await.unwrap();
+        installments::create_plan(&mut conn, contract_id, &plan)
+            .await
+            .unwrap();
+        drop(conn);
+        payments::pay_in_full(&pool, contract_id, &bd("2000.00"))
+            .await
+            .unwrap();
+        let payment_id =
+            sqlx::query_scalar::<_, i32>("SELECT id FROM payment WHERE contract_id = $1")
+                .bind(contract_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let open = |pool: sqlx::PgPool| async move {
+            installments::get_installments_for_contract(&pool, contract_id)
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|installment| installment.payment_id.is_none())
+                .map(|installment| installment.installment_number)
+                .collect::<Vec<i32>>()
+        };
+
+        payments::refund_payment(&pool, payment_id, Some(&bd("600.00")), "Two seats less")
+            .await
+            .unwrap();
+        let after_first = open(pool.clone()).await;
+        let overdue = installments::list_overdue(&pool, now + Duration::days(5))
+            .await
+            .unwrap();
+        // The open installments already cover what this refund adds
+        payments::refund_payment(&pool, payment_id, Some(&bd("100.00")), "One more")
+            .await
+            .unwrap();
+        let after_second = open(pool.clone()).await;
+
+        drop_scratch_database(admin, pool, database).await;
+
+        assert_eq!(after_first, vec![3, 4]);
+        assert_eq!(
+            overdue
+                .iter()
+                .map(|installment| (installment.installment_number, installment.amount.clone()))
+                .collect::<Vec<_>>(),
+            vec![(3, bd("500.00")), (4, bd("100.00"))]
+        );
+        assert_eq!(after_second, vec![3, 4]);
+    }
 }
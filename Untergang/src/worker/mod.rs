@@ -0,0 +1,100 @@
+use crate::db::{contracts, subscriptions};
+use chrono::Utc;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+
+// how often unpaid contracts and subscriptions are checked when nothing is configured
+pub const DEFAULT_EXPIRY_INTERVAL_SECS: u64 = 3600;
+
+// Reads the expiry interval in seconds, e.g. from CONTRACT_EXPIRY_INTERVAL_SECS
+pub fn parse_expiry_interval(value: Option<&str>) -> Result<Duration, String> {
+    let seconds = match value {
+        None => DEFAULT_EXPIRY_INTERVAL_SECS,
+        Some(value) => value
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid contract expiry interval: {}", value))?,
+    };
+    if seconds == 0 {
+        return Err("Contract expiry interval must be at least 1 second".to_string());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+// Runs the contract expiry and the subscription lapse in the background for as long as
+// the server is up
+pub fn spawn_expiry(pool: Pool<Postgres>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            expire_unpaid_contracts(&pool).await;
+            cancel_lapsed_subscriptions(&pool).await;
+        }
+    });
+}
+
+// Expires every unpaid contract past its end date, one transaction per contract so a
+// failure only skips that contract until the next run
+pub async fn expire_unpaid_contracts(pool: &Pool<Postgres>) {
+    let contract_ids = match contracts::find_expired_unpaid_contracts(pool).await {
+        Ok(contract_ids) => contract_ids,
+        Err(e) => {
+            tracing::error!(error = ?e, "Failed to look up expired contracts");
+            return;
+        }
+    };
+
+    let mut expired = 0;
+    let mut failed = 0;
+    for contract_id in contract_ids {
+        match contracts::expire_contract(pool, contract_id).await {
+            Ok(Some(refunded)) => {
+                expired += 1;
+                tracing::info!(contract_id, refunded = %refunded, "Expired unpaid contract");
+            }
+            Ok(None) => {
+                tracing::debug!(contract_id, "Contract no longer needs to expire");
+            }
+            Err(e) => {
+                failed += 1;
+                tracing::error!(contract_id, error = ?e, "Failed to expire contract");
+            }
+        }
+    }
+
+    if expired > 0 || failed > 0 {
+        tracing::info!(expired, failed, "Contract expiry run finished");
+    }
+}
+
+// Cancels every subscription with a renewal period that ended without being paid
+pub async fn cancel_lapsed_subscriptions(pool: &Pool<Postgres>) {
+    let subscription_ids = match subscriptions::find_active_subscriptions(pool).await {
+        Ok(subscription_ids) => subscription_ids,
+        Err(e) => {
+            tracing::error!(error = ?e, "Failed to look up active subscriptions");
+            return;
+        }
+    };
+
+    let mut cancelled = 0;
+    let mut failed = 0;
+    for subscription_id in subscription_ids {
+        match subscriptions::cancel_if_lapsed(pool, subscription_id, Utc::now()).await {
+            Ok(true) => {
+                cancelled += 1;
+                tracing::info!(subscription_id, "Cancelled lapsed subscription");
+            }
+            Ok(false) => {}
+            Err(e) => {
+                failed += 1;
+                tracing::error!(subscription_id, error = ?e, "Failed to cancel lapsed subscription");
+            }
+        }
+    }
+
+    if cancelled > 0 || failed > 0 {
+        tracing::info!(cancelled, failed, "Subscription lapse run finished");
+    }
+}